@@ -2,6 +2,7 @@
 import os
 import json
 import uuid
+from datetime import datetime, timezone
 import boto3
 import redis
 import requests
@@ -32,6 +33,7 @@ def get_redis_client():
 
 def lambda_handler(event, context):
     job_id = str(uuid.uuid4())
+    job_started_at = datetime.now(timezone.utc).isoformat()
     print(f"Starting Job: {job_id}")
 
     try:
@@ -43,6 +45,10 @@ def lambda_handler(event, context):
 
         bucket_name = payload.get('bucket')
         file_key = payload.get('key')
+        # Ask the converter for canonical XML (byte-identical for the same input)
+        canonical = bool(payload.get('canonical', False))
+        # Extra serialisations stored next to the XML: "json" and/or "protobuf"
+        outputs = list(payload.get('outputs') or [])
 
         print(f"Received Request -> Bucket: {bucket_name}, Key: {file_key}")
 
@@ -64,6 +70,8 @@ def lambda_handler(event, context):
         r = get_redis_client()
         r.set(f"job:{job_id}:processed", 0)
         r.expire(f"job:{job_id}:processed", 86400)
+        r.set(f"job:{job_id}:started_at", job_started_at, ex=86400)
+        r.sadd("jobs:active", job_id)
 
         batch = []
         chunk_counter = 0
@@ -72,12 +80,12 @@ def lambda_handler(event, context):
             batch.append(row)
             if len(batch) >= BATCH_SIZE:
                 chunk_counter += 1
-                send_batch_to_sqs(job_id, chunk_counter, batch)
+                send_batch_to_sqs(job_id, chunk_counter, batch, job_started_at, canonical, outputs)
                 batch = [] 
 
         if batch:
             chunk_counter += 1
-            send_batch_to_sqs(job_id, chunk_counter, batch)
+            send_batch_to_sqs(job_id, chunk_counter, batch, job_started_at, canonical, outputs)
 
         r.set(f"job:{job_id}:total", chunk_counter)
         r.expire(f"job:{job_id}:total", 86400)
@@ -107,10 +115,13 @@ def lambda_handler(event, context):
             'body': json.dumps({"error": str(e)})
         }
 
-def send_batch_to_sqs(job_id, chunk_id, data):
+def send_batch_to_sqs(job_id, chunk_id, data, job_started_at, canonical, outputs):
     payload = {
         "job_id": job_id,
         "chunk_id": chunk_id,
+        "job_started_at": job_started_at,
+        "canonical": canonical,
+        "outputs": outputs,
         "data": data 
     }
     
@@ -6,6 +6,7 @@ import requests
 import io
 import csv
 import asyncio
+from datetime import datetime, timezone
 import numpy as np
 import yfinance as yf
 import pandas as pd
@@ -16,6 +17,8 @@ REDIS_PASSWORD = os.environ.get('REDIS_PASSWORD', None)
 S3_BUCKET_NAME = os.environ.get('S3_BUCKET_NAME')
 COBOL_HOST = os.environ.get('COBOL_HOST')
 COBOL_PORT = int(os.environ.get('COBOL_PORT', 8080))
+# The converter's input queue; same variable and default as its queues.csv_processing
+QUEUE_CSV_PROCESSING = os.environ.get('QUEUE_CSV_PROCESSING') or 'queue:csv_processing'
 
 s3 = boto3.client('s3')
 
@@ -166,9 +169,24 @@ async def process_chunk(record, redis_client):
         "job_id": job_id, 
         "s3_bucket": S3_BUCKET_NAME, 
         "s3_key": s3_key, 
-        "chunk_id": chunk_id
+        "format": "csv",
+        "chunk_id": chunk_id,
+        "generated_at": payload.get('job_started_at'),
+        "canonical": payload.get('canonical', False),
+        "outputs": payload.get('outputs', [])
     })
-    redis_client.rpush("queue:xml_processing", msg)
+    redis_client.rpush(QUEUE_CSV_PROCESSING, msg)
+
+    # Per-chunk state, continued by the Rust workers (see chunk_state.rs)
+    now = datetime.now(timezone.utc).isoformat()
+    chunk_key = f"job:{job_id}:chunk:{chunk_id}"
+    tracked_key = f"job:{job_id}:tracked_chunks"
+    pipe = redis_client.pipeline()
+    pipe.hset(chunk_key, mapping={"state": "queued", "updated_at": now, "queued_at": now})
+    pipe.sadd(tracked_key, chunk_id)
+    pipe.expire(chunk_key, 86400)
+    pipe.expire(tracked_key, 86400)
+    pipe.execute()
 
 def lambda_handler(event, context):
     r = get_redis_client()
@@ -1,5 +1,8 @@
 import json
 import os
+import time
+import hmac
+import hashlib
 import boto3
 from supabase import create_client, Client
 
@@ -7,23 +10,65 @@ SUPABASE_URL = os.environ.get('SUPABASE_URL')
 SUPABASE_KEY = os.environ.get('SUPABASE_KEY')
 SUPABASE_BUCKET = 'tp3_bucket' 
 AWS_BUCKET = os.environ.get('S3_BUCKET_NAME') 
+WEBHOOK_SECRET = os.environ.get('WEBHOOK_SECRET')
+# Signed notifications older (or further in the future) than this are replayed or forged
+WEBHOOK_TOLERANCE_SECS = int(os.environ.get('WEBHOOK_TOLERANCE_SECS', '300'))
+
+# Job status vocabulary sent by db_sender (xml_service/db_sender/WEBHOOKS.md)
+JOB_COMPLETED = "COMPLETED"
+JOB_COMPLETED_WITH_ERRORS = "COMPLETED_WITH_ERRORS"
+JOB_TIMED_OUT = "TIMED_OUT"
+JOB_CANCELLED = "CANCELLED"
+KNOWN_STATUSES = {JOB_COMPLETED, JOB_COMPLETED_WITH_ERRORS, JOB_TIMED_OUT, JOB_CANCELLED}
 
 s3 = boto3.client('s3')
 supabase: Client = create_client(SUPABASE_URL, SUPABASE_KEY)
 
+def signature_is_valid(event):
+    # db_sender signs "<timestamp>.<body>" with HMAC-SHA256 when WEBHOOK_SECRET is set
+    if not WEBHOOK_SECRET:
+        return True
+    headers = {k.lower(): v for k, v in (event.get('headers') or {}).items()}
+    timestamp = headers.get('x-webhook-timestamp', '')
+    received = headers.get('x-webhook-signature', '')
+    try:
+        if abs(time.time() - int(timestamp)) > WEBHOOK_TOLERANCE_SECS:
+            return False
+    except ValueError:
+        return False
+    message = f"{timestamp}.{event.get('body', '')}".encode()
+    expected = "sha256=" + hmac.new(WEBHOOK_SECRET.encode(), message, hashlib.sha256).hexdigest()
+    return hmac.compare_digest(expected, received)
+
 def lambda_handler(event, context):
     try:
+        if not signature_is_valid(event):
+            return {
+                'statusCode': 401,
+                'body': json.dumps({"error": "Invalid webhook signature"})
+            }
+
         body = json.loads(event.get('body', '{}'))
         
         job_id = body.get('job_id')
         status = body.get('status')
+        chunks = body.get('chunks', {})
         
-        print(f"Webhook received for Job {job_id} with Status: {status}")
+        print(f"Webhook v{body.get('version')} received for Job {job_id} with Status: {status} "
+              f"({chunks.get('succeeded')}/{chunks.get('total')} chunks ok)")
         
-        if not job_id or status != "COMPLETED":
+        if not job_id or status not in KNOWN_STATUSES:
             return {
                 'statusCode': 400,
-                'body': json.dumps({"error": "Invalid Request or Job not complete"})
+                'body': json.dumps({"error": "Invalid Request"})
+            }
+
+        # Only a clean run is cleaned up; failed chunks are kept for the error report.
+        # Still answer 2xx so db_sender doesn't retry a notification we understood.
+        if status != JOB_COMPLETED:
+            return {
+                'statusCode': 200,
+                'body': json.dumps({"message": "Job finished with errors, nothing cleaned", "job_id": job_id})
             }
         prefix = f"processed/{job_id}/"
         
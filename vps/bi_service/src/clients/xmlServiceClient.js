@@ -138,8 +138,10 @@ async function getXmlData(queryType = 'allAssets', filters = {}) {
         
         // 5. Advanced/Structural Queries
         countAssets: 'count(//Asset)',
-        jobID: '/MarketReport/@JobID',
-        reportMetadata: '/MarketReport'
+        // A raiz é mr:MarketReport nos documentos versionados e MarketReport nos
+        // antigos; local-name() apanha as duas
+        jobID: "/*[local-name()='MarketReport']/@JobID",
+        reportMetadata: "/*[local-name()='MarketReport']"
       };
       
       // Se queryType começa com '//' ou '/', é uma query XPath custom
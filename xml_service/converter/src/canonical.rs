@@ -0,0 +1,112 @@
+use anyhow::Result;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+
+// Canonical form of a document, loosely after XML C14N, so identical input
+// gives byte-identical output whatever produced it:
+//   - a UTF-8 declaration, LF line endings and a single trailing newline
+//   - namespace declarations first, then the other attributes, each group
+//     sorted by name, values in double quotes with the same escaping
+//   - empty elements written as start/end pairs (<a></a>, not <a/>)
+//   - whitespace-only text dropped and the tree indented with two spaces;
+//     other text is kept as it is
+//   - comments, processing instructions and the original declaration dropped
+pub fn canonicalise(xml: &str) -> Result<String> {
+    let mut reader = Reader::from_str(xml);
+    let mut canonical = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>");
+    let mut depth = 0;
+    // An element's end tag stays on its line when nothing but text came since its start tag
+    let mut inline = false;
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => {
+                newline(&mut canonical, depth);
+                canonical.push_str(&format!("<{}>", std::str::from_utf8(&sorted(&e)?)?));
+                depth += 1;
+                inline = true;
+            }
+            Event::Empty(e) => {
+                newline(&mut canonical, depth);
+                let start = sorted(&e)?;
+                canonical.push_str(&format!("<{}></{}>", std::str::from_utf8(&start)?, std::str::from_utf8(start.name().as_ref())?));
+                inline = false;
+            }
+            Event::End(e) => {
+                depth -= 1;
+                if !inline {
+                    newline(&mut canonical, depth);
+                }
+                canonical.push_str(&format!("</{}>", std::str::from_utf8(e.name().as_ref())?));
+                inline = false;
+            }
+            Event::Text(t) if t.iter().all(u8::is_ascii_whitespace) => (),
+            Event::Text(t) => canonical.push_str(std::str::from_utf8(&t)?),
+            Event::CData(c) => canonical.push_str(&format!("<![CDATA[{}]]>", std::str::from_utf8(&c)?)),
+            Event::Decl(_) | Event::Comment(_) | Event::PI(_) | Event::DocType(_) => (),
+            Event::Eof => break,
+        }
+    }
+    canonical.push('\n');
+    Ok(canonical.replace("\r\n", "\n"))
+}
+
+fn newline(canonical: &mut String, depth: usize) {
+    canonical.push('\n');
+    canonical.push_str(&"  ".repeat(depth));
+}
+
+fn sorted(element: &BytesStart) -> Result<BytesStart<'static>> {
+    let mut attributes = Vec::new();
+    for attr in element.attributes() {
+        let attr = attr?;
+        let name = String::from_utf8(attr.key.as_ref().to_vec())?;
+        let value = attr.unescape_value()?.into_owned();
+        attributes.push((!is_namespace_declaration(&name), name, value));
+    }
+    attributes.sort();
+    let mut sorted = BytesStart::new(String::from_utf8(element.name().as_ref().to_vec())?);
+    for (_, name, value) in &attributes {
+        sorted.push_attribute((name.as_str(), value.as_str()));
+    }
+    Ok(sorted)
+}
+
+fn is_namespace_declaration(name: &str) -> bool {
+    name == "xmlns" || name.starts_with("xmlns:")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_document_in_any_layout_gives_the_same_bytes() {
+        let a = r#"<?xml version="1.0"?><mr:R xmlns:mr="urn:x" JobID="j" ChunkID="1"><Asset Ticker="A"><Name>Apple Inc.</Name><Beta/></Asset></mr:R>"#;
+        let b = "<mr:R ChunkID='1' JobID=\"j\" xmlns:mr=\"urn:x\">\r\n    <!-- note -->\r\n    <Asset Ticker=\"A\">\r\n\t<Name>Apple Inc.</Name>\r\n\t<Beta></Beta>\r\n    </Asset>\r\n</mr:R>";
+        let expected = concat!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+            "<mr:R xmlns:mr=\"urn:x\" ChunkID=\"1\" JobID=\"j\">\n",
+            "  <Asset Ticker=\"A\">\n",
+            "    <Name>Apple Inc.</Name>\n",
+            "    <Beta></Beta>\n",
+            "  </Asset>\n",
+            "</mr:R>\n",
+        );
+        assert_eq!(canonicalise(a).unwrap(), expected);
+        assert_eq!(canonicalise(b).unwrap(), expected);
+    }
+
+    #[test]
+    fn is_idempotent_and_keeps_text_and_escapes() {
+        let xml = r#"<R Name="A &amp; B"><T> padded &lt;text&gt; </T></R>"#;
+        let once = canonicalise(xml).unwrap();
+        assert!(once.contains(r#"<R Name="A &amp; B">"#), "{}", once);
+        assert!(once.contains("<T> padded &lt;text&gt; </T>"), "{}", once);
+        assert_eq!(canonicalise(&once).unwrap(), once);
+    }
+
+    #[test]
+    fn rejects_malformed_documents() {
+        assert!(canonicalise("<R><A></R>").is_err());
+    }
+}
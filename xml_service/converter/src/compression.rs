@@ -0,0 +1,129 @@
+use anyhow::{bail, Context, Result};
+use std::io::Read;
+
+use crate::storage::StoredObject;
+
+// Upper bound on a decompressed chunk. Chunks are a few thousand rows; a
+// small object inflating past this is a compression bomb, not a chunk.
+const MAX_DECOMPRESSED_BYTES: u64 = 256 * 1024 * 1024;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+#[derive(Debug, PartialEq)]
+enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+// The declared encoding wins; objects uploaded without one fall back to sniffing magic bytes
+fn detect(obj: &StoredObject) -> Result<Compression> {
+    if let Some(encoding) = &obj.content_encoding {
+        match encoding.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => return Ok(Compression::Gzip),
+            "zstd" => return Ok(Compression::Zstd),
+            "" | "identity" => (),
+            other => bail!("Unsupported content encoding '{}'", other),
+        }
+    }
+    if obj.data.starts_with(GZIP_MAGIC) {
+        Ok(Compression::Gzip)
+    } else if obj.data.starts_with(ZSTD_MAGIC) {
+        Ok(Compression::Zstd)
+    } else {
+        Ok(Compression::None)
+    }
+}
+
+pub fn decompress(obj: StoredObject) -> Result<Vec<u8>> {
+    decompress_at_most(obj, MAX_DECOMPRESSED_BYTES)
+}
+
+fn decompress_at_most(obj: StoredObject, limit: u64) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    match detect(&obj)? {
+        Compression::None => return Ok(obj.data),
+        Compression::Gzip => {
+            flate2::read::MultiGzDecoder::new(obj.data.as_slice())
+                .take(limit + 1)
+                .read_to_end(&mut out)
+                .context("Failed to decompress gzip chunk")?;
+        }
+        Compression::Zstd => {
+            zstd::stream::read::Decoder::new(obj.data.as_slice())?
+                .take(limit + 1)
+                .read_to_end(&mut out)
+                .context("Failed to decompress zstd chunk")?;
+        }
+    }
+    if out.len() as u64 > limit {
+        bail!("Chunk decompresses to more than {} bytes ({} compressed)", limit, obj.data.len());
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const CSV: &[u8] = b"Ticker,Nome\nAAPL,Apple Inc.\n";
+
+    fn object(data: Vec<u8>, content_encoding: Option<&str>) -> StoredObject {
+        StoredObject { data, content_encoding: content_encoding.map(str::to_string) }
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn zstd(data: &[u8]) -> Vec<u8> {
+        zstd::stream::encode_all(data, 0).unwrap()
+    }
+
+    #[test]
+    fn round_trips_declared_encodings() {
+        assert_eq!(decompress(object(gzip(CSV), Some("gzip"))).unwrap(), CSV);
+        assert_eq!(decompress(object(gzip(CSV), Some(" X-GZIP "))).unwrap(), CSV);
+        assert_eq!(decompress(object(zstd(CSV), Some("zstd"))).unwrap(), CSV);
+        assert_eq!(decompress(object(CSV.to_vec(), Some("identity"))).unwrap(), CSV);
+        assert_eq!(decompress(object(CSV.to_vec(), None)).unwrap(), CSV);
+    }
+
+    #[test]
+    fn sniffs_magic_bytes_without_an_encoding() {
+        assert_eq!(detect(&object(gzip(CSV), None)).unwrap(), Compression::Gzip);
+        assert_eq!(detect(&object(zstd(CSV), None)).unwrap(), Compression::Zstd);
+        assert_eq!(detect(&object(CSV.to_vec(), Some(""))).unwrap(), Compression::None);
+        assert_eq!(decompress(object(gzip(CSV), None)).unwrap(), CSV);
+        assert_eq!(decompress(object(zstd(CSV), Some("identity"))).unwrap(), CSV);
+    }
+
+    #[test]
+    fn concatenated_gzip_members_are_read_whole() {
+        let mut data = gzip(b"Ticker,Nome\n");
+        data.extend(gzip(b"AAPL,Apple Inc.\n"));
+        assert_eq!(decompress(object(data, None)).unwrap(), CSV);
+    }
+
+    #[test]
+    fn rejects_unsupported_and_corrupt_input() {
+        let err = decompress(object(CSV.to_vec(), Some("br"))).unwrap_err();
+        assert_eq!(err.to_string(), "Unsupported content encoding 'br'");
+        assert!(decompress(object(CSV.to_vec(), Some("gzip"))).is_err());
+    }
+
+    #[test]
+    fn stops_at_the_size_limit() {
+        let bomb = vec![b'0'; 64 * 1024];
+        for compressed in [gzip(&bomb), zstd(&bomb)] {
+            assert!(compressed.len() < 1024);
+            let err = decompress_at_most(object(compressed.clone(), None), 4096).unwrap_err();
+            assert!(err.to_string().contains("more than 4096 bytes"), "{}", err);
+            assert_eq!(decompress_at_most(object(compressed, None), bomb.len() as u64).unwrap().len(), bomb.len());
+        }
+    }
+}
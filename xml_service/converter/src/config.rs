@@ -0,0 +1,65 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use xml_common::config::{EnvKeys, LogConfig, MetricsConfig, Queues, RedisConfig, Settings, WorkerConfig};
+
+// The converter's configuration; see xml_common::config for how it's loaded
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub redis: RedisConfig,
+    pub queues: Queues,
+    pub worker: WorkerConfig,
+    pub object_store: ObjectStoreConfig,
+    pub metrics: MetricsConfig,
+    pub log: LogConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            redis: RedisConfig::default(),
+            queues: Queues::default(),
+            worker: WorkerConfig::default(),
+            object_store: ObjectStoreConfig::default(),
+            metrics: MetricsConfig { port: 9101 },
+            log: LogConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ObjectStoreConfig {
+    pub url: String,
+    // Bucket probed by the readiness check; empty means the last bucket read
+    pub health_bucket: String,
+    // Bucket every converted chunk is also written to, as <job_id>/chunk_<n>.xml;
+    // empty writes nothing
+    pub output_bucket: String,
+}
+
+impl Default for ObjectStoreConfig {
+    fn default() -> Self {
+        ObjectStoreConfig { url: "s3://".to_string(), health_bucket: String::new(), output_bucket: String::new() }
+    }
+}
+
+impl Settings for Config {
+    const ENV_KEYS: &'static [(&'static str, &'static str)] = &[
+        ("object_store.url", "OBJECT_STORE_URL"),
+        ("object_store.health_bucket", "S3_BUCKET_NAME"),
+        ("object_store.output_bucket", "OUTPUT_BUCKET_NAME"),
+    ];
+
+    fn validate(&self, keys: &EnvKeys) -> Result<()> {
+        self.redis.validate(keys)?;
+        self.queues.validate(keys)?;
+        self.worker.validate(keys)?;
+        self.metrics.validate(keys)?;
+        if !self.object_store.url.contains("://") {
+            bail!("object_store.url must look like <scheme>://..., got '{}'", self.object_store.url);
+        }
+        self.log.validate()
+    }
+}
@@ -0,0 +1,184 @@
+use anyhow::{bail, Context, Result};
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::record::Field;
+use serde_json::{Map, Value};
+
+use crate::InputRow;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputFormat {
+    Csv,
+    JsonLines,
+    Parquet,
+}
+
+// An explicit `format` on the message wins, otherwise the object extension
+// (ignoring a trailing compression suffix) decides. Anything unknown is CSV.
+pub fn resolve(format: Option<&str>, key: &str) -> Result<InputFormat> {
+    if let Some(format) = format {
+        return match format.to_ascii_lowercase().as_str() {
+            "csv" => Ok(InputFormat::Csv),
+            "jsonl" | "ndjson" | "jsonlines" => Ok(InputFormat::JsonLines),
+            "parquet" => Ok(InputFormat::Parquet),
+            other => bail!("Unsupported input format '{}'", other),
+        };
+    }
+    let key = key.to_ascii_lowercase();
+    let key = [".gz", ".zst", ".zstd"].iter().find_map(|ext| key.strip_suffix(ext)).unwrap_or(&key);
+    if key.ends_with(".jsonl") || key.ends_with(".ndjson") {
+        Ok(InputFormat::JsonLines)
+    } else if key.ends_with(".parquet") {
+        Ok(InputFormat::Parquet)
+    } else {
+        Ok(InputFormat::Csv)
+    }
+}
+
+pub fn read_rows(format: InputFormat, data: Vec<u8>) -> Result<Vec<InputRow>> {
+    match format {
+        InputFormat::Csv => read_csv(data),
+        InputFormat::JsonLines => read_json_lines(data),
+        InputFormat::Parquet => read_parquet(data),
+    }
+}
+
+fn read_csv(data: Vec<u8>) -> Result<Vec<InputRow>> {
+    let csv_str = String::from_utf8(data)?;
+    let mut reader = csv::Reader::from_reader(csv_str.as_bytes());
+    let mut rows = Vec::new();
+    for result in reader.deserialize() {
+        rows.push(result?);
+    }
+    Ok(rows)
+}
+
+// JSON Lines and Parquet rows use the same column names as the CSV header.
+// Their values are flattened to strings so they land in the same `InputRow`.
+fn row_from_map(map: Map<String, Value>) -> Result<InputRow> {
+    let fields = map
+        .into_iter()
+        .filter_map(|(k, v)| match v {
+            Value::Null => None,
+            Value::String(s) => Some((k, Value::String(s))),
+            other => Some((k, Value::String(other.to_string()))),
+        })
+        .collect();
+    Ok(serde_json::from_value(Value::Object(fields))?)
+}
+
+fn read_json_lines(data: Vec<u8>) -> Result<Vec<InputRow>> {
+    let text = String::from_utf8(data)?;
+    let mut rows = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let map: Map<String, Value> = serde_json::from_str(line).with_context(|| format!("Invalid JSON on line {}", n + 1))?;
+        rows.push(row_from_map(map).with_context(|| format!("Invalid row on line {}", n + 1))?);
+    }
+    Ok(rows)
+}
+
+fn read_parquet(data: Vec<u8>) -> Result<Vec<InputRow>> {
+    let reader = SerializedFileReader::new(bytes::Bytes::from(data)).context("Invalid Parquet file")?;
+    let mut rows = Vec::new();
+    for record in reader.get_row_iter(None)? {
+        let record = record?;
+        let mut map = Map::new();
+        for (name, field) in record.get_column_iter() {
+            let value = match field {
+                Field::Null => Value::Null,
+                Field::Str(s) => Value::String(s.clone()),
+                other => Value::String(other.to_string()),
+            };
+            map.insert(name.clone(), value);
+        }
+        rows.push(row_from_map(map)?);
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn explicit_format_wins_over_the_extension() {
+        assert_eq!(resolve(Some("JSONL"), "chunk.csv").unwrap(), InputFormat::JsonLines);
+        assert_eq!(resolve(Some("parquet"), "chunk.csv").unwrap(), InputFormat::Parquet);
+        assert!(resolve(Some("xlsx"), "chunk.csv").is_err());
+    }
+
+    #[test]
+    fn extension_decides_ignoring_compression() {
+        assert_eq!(resolve(None, "jobs/1/chunk_0.ndjson.gz").unwrap(), InputFormat::JsonLines);
+        assert_eq!(resolve(None, "jobs/1/CHUNK_0.PARQUET").unwrap(), InputFormat::Parquet);
+        assert_eq!(resolve(None, "jobs/1/chunk_0.csv.zst").unwrap(), InputFormat::Csv);
+        assert_eq!(resolve(None, "jobs/1/chunk_0").unwrap(), InputFormat::Csv);
+    }
+
+    #[test]
+    fn json_lines_flatten_values_to_strings() {
+        let data = b"{\"Ticker\":\"AAPL\",\"Nome\":\"Apple\",\"Sector\":\"Tech\",\"PriceSMA_EUR\":180.5,\"VolumeAvg\":1000,\"Beta (5Y Monthly)\":null}\n\n";
+        let rows = read_rows(InputFormat::JsonLines, data.to_vec()).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].price_sma, "180.5");
+        assert_eq!(rows[0].volume_avg, "1000");
+        assert_eq!(rows[0].beta, None);
+
+        let err = read_rows(InputFormat::JsonLines, b"{\"Ticker\":1}\nnot json".to_vec()).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid row on line 1");
+    }
+
+    // Typed columns (double, int64, optional) written the way pandas/pyarrow would
+    fn parquet_chunk() -> Vec<u8> {
+        use parquet::data_type::{ByteArray, ByteArrayType, DoubleType, Int64Type};
+        use parquet::file::writer::SerializedFileWriter;
+        use parquet::schema::parser::parse_message_type;
+        use std::sync::Arc;
+
+        let schema = Arc::new(parse_message_type(
+            "message chunk {
+                REQUIRED BYTE_ARRAY Ticker (UTF8);
+                REQUIRED BYTE_ARRAY Nome (UTF8);
+                REQUIRED BYTE_ARRAY Sector (UTF8);
+                REQUIRED DOUBLE PriceSMA_EUR;
+                REQUIRED INT64 VolumeAvg;
+                OPTIONAL DOUBLE Open;
+            }",
+        ).unwrap());
+        let strings = |values: &[&str]| values.iter().map(|v| ByteArray::from(*v)).collect::<Vec<_>>();
+        let mut data = Vec::new();
+        let mut writer = SerializedFileWriter::new(&mut data, schema, Default::default()).unwrap();
+        let mut group = writer.next_row_group().unwrap();
+        for values in [strings(&["AAPL", "MSFT"]), strings(&["Apple", "Microsoft"]), strings(&["Tech", "Tech"])] {
+            let mut column = group.next_column().unwrap().unwrap();
+            column.typed::<ByteArrayType>().write_batch(&values, None, None).unwrap();
+            column.close().unwrap();
+        }
+        let mut column = group.next_column().unwrap().unwrap();
+        column.typed::<DoubleType>().write_batch(&[180.5, 410.25], None, None).unwrap();
+        column.close().unwrap();
+        let mut column = group.next_column().unwrap().unwrap();
+        column.typed::<Int64Type>().write_batch(&[1000, 2500], None, None).unwrap();
+        column.close().unwrap();
+        let mut column = group.next_column().unwrap().unwrap();
+        column.typed::<DoubleType>().write_batch(&[1.2], Some(&[1, 0]), None).unwrap();
+        column.close().unwrap();
+        group.close().unwrap();
+        writer.close().unwrap();
+        data
+    }
+
+    #[test]
+    fn parquet_rows_flatten_values_to_strings() {
+        let rows = read_rows(InputFormat::Parquet, parquet_chunk()).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!((rows[0].ticker.as_str(), rows[0].name.as_str(), rows[0].sector.as_str()), ("AAPL", "Apple", "Tech"));
+        assert_eq!((rows[0].price_sma.as_str(), rows[0].volume_avg.as_str()), ("180.5", "1000"));
+        assert_eq!((rows[1].ticker.as_str(), rows[1].price_sma.as_str(), rows[1].volume_avg.as_str()), ("MSFT", "410.25", "2500"));
+        assert_eq!((rows[0].open_price.as_deref(), rows[1].open_price.as_deref()), (Some("1.2"), None));
+
+        assert!(read_rows(InputFormat::Parquet, b"not parquet".to_vec()).is_err());
+    }
+}
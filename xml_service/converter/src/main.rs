@@ -1,12 +1,26 @@
-use anyhow::{Context, Result};
-use aws_sdk_s3::Client as S3Client;
-use chrono::Utc;
-use redis::AsyncCommands;
+mod canonical;
+mod compression;
+mod config;
+mod formats;
+mod metrics;
+mod outputs;
+mod storage;
+#[cfg(test)]
+mod xsd;
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::env;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use storage::ObjectStore;
+use tracing::Instrument;
+use xml_common::{cancel, chunk_state, health, logging, redis_conn, shutdown, telemetry, worker};
 
 #[derive(Debug, Deserialize)]
-struct CsvRow { 
+#[allow(non_snake_case)]
+struct InputRow {
     #[serde(rename = "Ticker")]
     ticker: String,
     #[serde(rename = "Nome")]
@@ -41,9 +55,32 @@ struct CsvRow {
     Price_10: Option<String>, Volume_10: Option<String>,
 }
 
+// Layout revision of the emitted document. Bump the version and namespace
+// together whenever the element structure changes; the validator and the
+// gRPC server key their rules and XPath prefixes off these.
+const SCHEMA_VERSION: &str = "1.0";
+const SCHEMA_NAMESPACE: &str = "urn:tp3:market-report:v1";
+const SCHEMA_LOCATION: &str = "urn:tp3:market-report:v1 market-report-v1.xsd";
+const XSI_NAMESPACE: &str = "http://www.w3.org/2001/XMLSchema-instance";
+
+// Version of the row-to-document mapping in build_report. Bump it whenever the
+// mapping changes; `db_sender replay` re-enqueues old jobs tagged with the new
+// version and db_sender supersedes the rows stored under the previous one.
+const MAPPER_VERSION: &str = "1.0.0";
+
+// Only the root is namespace-qualified (mr:MarketReport); child elements stay
+// unqualified so existing `//Asset` style XPath queries keep matching.
 #[derive(Debug, Serialize)]
-#[serde(rename = "MarketReport")]
+#[serde(rename = "mr:MarketReport")]
 struct MarketReport {
+    #[serde(rename = "@xmlns:mr")]
+    namespace: &'static str,
+    #[serde(rename = "@xmlns:xsi")]
+    xsi_namespace: &'static str,
+    #[serde(rename = "@xsi:schemaLocation")]
+    schema_location: &'static str,
+    #[serde(rename = "@SchemaVersion")]
+    schema_version: &'static str,
     #[serde(rename = "@JobID")]
     job_id: String,
     #[serde(rename = "@ChunkID")]
@@ -77,6 +114,7 @@ struct Identification {
 }
 
 #[derive(Debug, Serialize)]
+#[allow(non_snake_case)]
 struct FundamentalData {
     MarketCap: String,
     PERatio: String,
@@ -124,6 +162,30 @@ struct InputMsg {
     s3_bucket: String,
     s3_key: String,
     chunk_id: u32,
+    // csv, jsonl or parquet, declared by whoever wrote the object (the
+    // enricher always writes CSV). Without it the object's extension decides.
+    #[serde(default)]
+    format: Option<String>,
+    // Serialisations requested per job through the chunker (`"outputs": ["json", "protobuf"]`),
+    // rendered next to the XML and stored by db_sender in report_outputs
+    #[serde(default)]
+    outputs: Vec<String>,
+    // Job start time set by the chunker. When present it becomes GeneratedAt,
+    // so re-converting the same chunk yields the same bytes.
+    #[serde(default)]
+    generated_at: Option<String>,
+    // Requested per job through the chunker (`"canonical": true`): write the
+    // document in canonical form, so together with generated_at the same
+    // input always gives byte-identical XML (content hashes, golden tests)
+    #[serde(default)]
+    canonical: bool,
+    // Set by `db_sender replay`: the mapper version the chunk must be converted
+    // with. A converter running another version refuses the chunk.
+    #[serde(default)]
+    mapper_version: Option<String>,
+    // W3C trace context (traceparent) of the sender, see telemetry.rs
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    trace_context: HashMap<String, String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -132,13 +194,44 @@ struct XmlMsg {
     chunk_id: u32,
     xml_content: String,
     mapper_version: String,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    outputs: HashMap<String, String>,
+    // The input message this chunk was converted from, kept by db_sender so
+    // the chunk can be replayed later
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<serde_json::Value>,
+    // W3C trace context (traceparent) of the sender, see telemetry.rs
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    trace_context: HashMap<String, String>,
+}
+
+// Status of a chunk the converter couldn't build a document for. It goes
+// straight to db_sender (mirroring its PipelineMsg) so the chunk is
+// quarantined and counted, and the job still completes, with errors.
+const CONVERSION_FAILED: &str = "ERRO_CONVERSAO";
+
+#[derive(Serialize, Debug)]
+struct FailedMsg {
+    job_id: String,
+    chunk_id: u32,
+    xml_content: String,
+    status: &'static str,
+    mapper_version: String,
+    findings: Vec<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    trace_context: HashMap<String, String>,
+}
+
+struct ConvertedChunk {
+    xml: String,
+    outputs: HashMap<String, String>,
 }
 
-fn convert_to_xml(csv_data: String, job_id: &str, chunk_id: u32) -> Result<String> {
-    let mut reader = csv::Reader::from_reader(csv_data.as_bytes());
+fn build_report(rows: Vec<InputRow>, job_id: &str, chunk_id: u32, generated_at: String) -> MarketReport {
     let mut assets = Vec::new();
-    for result in reader.deserialize() {
-        let row: CsvRow = result?; 
+    for row in rows {
         let fundamentals = FundamentalData {
             MarketCap: row.market_cap.unwrap_or("Unknown".into()),
             PERatio: row.pe_ratio.unwrap_or("".into()),
@@ -154,7 +247,8 @@ fn convert_to_xml(csv_data: String, job_id: &str, chunk_id: u32) -> Result<Strin
                     days.push(Day {
                         index: idx,
                         closing_price: Price { currency: "EUR".into(), value: price },
-                        volume: v.unwrap_or("0".into()),
+                        // Left empty when missing, so a real 0 stays distinguishable
+                        volume: v.unwrap_or_default(),
                     });
                 }
             }
@@ -179,71 +273,301 @@ fn convert_to_xml(csv_data: String, job_id: &str, chunk_id: u32) -> Result<Strin
         });
     }
 
-    let report = MarketReport {
+    MarketReport {
+        namespace: SCHEMA_NAMESPACE,
+        xsi_namespace: XSI_NAMESPACE,
+        schema_location: SCHEMA_LOCATION,
+        schema_version: SCHEMA_VERSION,
         job_id: job_id.to_string(),
         chunk_id,
-        generated_at: Utc::now().to_rfc3339(),
+        generated_at,
         assets,
-    };
+    }
+}
+
+fn resolve_generated_at(input: &InputMsg) -> Result<String> {
+    match &input.generated_at {
+        Some(ts) => {
+            let parsed = DateTime::parse_from_rfc3339(ts).with_context(|| format!("Invalid generated_at '{}'", ts))?;
+            Ok(parsed.with_timezone(&Utc).to_rfc3339())
+        }
+        None => Ok(Utc::now().to_rfc3339()),
+    }
+}
 
+// `canonical` rewrites the document in canonical form (see canonical.rs). It
+// starts from the unindented serialisation, since the indenting one also pads
+// text values such as ClosingPrice with whitespace.
+fn convert_to_xml(report: &MarketReport, canonical: bool) -> Result<String> {
     let mut xml_string = String::new();
     let mut serializer = quick_xml::se::Serializer::new(&mut xml_string);
-    serializer.indent(' ', 2);
+    if !canonical {
+        serializer.indent(' ', 2);
+    }
     report.serialize(serializer)?;
+    if canonical {
+        return canonical::canonicalise(&xml_string);
+    }
     Ok(xml_string)
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let redis_host = env::var("REDIS_HOST").context("REDIS_HOST missing")?;
-    let redis_port = env::var("REDIS_PORT").unwrap_or("6379".to_string());
-    let redis_password = env::var("REDIS_PASSWORD").unwrap_or_default();
-    let aws_config = aws_config::load_from_env().await;
-    let s3_client = S3Client::new(&aws_config);
-
-    let redis_url = if !redis_password.is_empty() {
-        format!("redis://:{}@{}:{}", redis_password, redis_host, redis_port)
-    } else {
-        format!("redis://{}:{}", redis_host, redis_port)
-    };
-
-    let client = redis::Client::open(redis_url)?;
-    let mut con = client.get_tokio_connection().await?;
-
-    println!("Converter Service Started. Listening on 'queue:csv_processing'...");
-
-    loop {
-        let result: Option<(String, String)> = con.blpop("queue:csv_processing", 0.0).await?;
-        if let Some((_, json_str)) = result {
-            let input: InputMsg = match serde_json::from_str(&json_str) {
-                Ok(msg) => msg,
-                Err(e) => {
-                    eprintln!("Failed to parse Redis message: {}", e);
-                    continue;
-                }
+    let (config, rest) = xml_common::config::load::<config::Config>(std::env::args().skip(1).collect())?;
+    if let Some(arg) = rest.first() {
+        bail!("Unexpected argument '{}'", arg);
+    }
+    let config = Arc::new(config);
+    logging::init(env!("CARGO_PKG_NAME"), &config.log);
+    let store: Arc<dyn ObjectStore> = storage::from_config(&config.object_store).await?.into();
+
+    let client = redis_conn::Redis::open(&config.redis).context("Invalid redis settings")?;
+
+    let queues = &config.queues;
+    tokio::spawn(metrics::serve(config.metrics.port, health::liveness, health::readiness));
+    tokio::spawn(metrics::watch_queues(client.clone(), vec![queues.csv_processing.clone(), queues.xml_validation.clone()]));
+    let health_client = client.clone();
+    tokio::spawn(health::watch("redis", move || health::redis_check(health_client.clone())));
+    let health_store = store.clone();
+    tokio::spawn(health::watch("object_store", move || {
+        let store = health_store.clone();
+        async move { store.check().await }
+    }));
+
+    let shutdown = shutdown::Shutdown::listen(Duration::from_secs(config.worker.shutdown_timeout_secs));
+    let concurrency = config.worker.concurrency;
+    tracing::info!(redis = %client.target(), object_store = %config.object_store.url, queue = %queues.csv_processing, concurrency, "service.started");
+
+    let converters = (0..concurrency).map(|_| Converter { store: store.clone(), config: config.clone() }).collect();
+    worker::run(&client, &queues.csv_processing, config.worker.poll_timeout_secs, converters, &shutdown).await?;
+
+    tracing::info!("service.stopped");
+    telemetry::shutdown();
+    Ok(())
+}
+
+// One of WORKER_CONCURRENCY consumers, each with its own Redis connection so a
+// BLPOP never waits behind another worker's commands. Ordering: chunks are
+// popped in queue order but with more than one worker they can reach
+// queue:xml_validation in any order. Nothing downstream depends on chunk
+// order; job completion is counted per chunk in db_sender.
+struct Converter {
+    store: Arc<dyn ObjectStore>,
+    config: Arc<config::Config>,
+}
+
+impl worker::Handler for Converter {
+    type Message = InputMsg;
+
+    fn span(&self, worker: usize, input: &InputMsg) -> tracing::Span {
+        let span = tracing::info_span!(
+            "chunk",
+            stage = "convert",
+            worker,
+            job_id = %input.job_id,
+            chunk_id = input.chunk_id,
+            mapper_version = input.mapper_version.as_deref().unwrap_or(MAPPER_VERSION),
+        );
+        telemetry::set_parent(&span, &input.trace_context);
+        span
+    }
+
+    // A requeued chunk is converted again from scratch
+    async fn handle(&self, con: &mut redis_conn::Connection, input: InputMsg, payload: &str) -> Result<()> {
+        let config = &self.config;
+        handle_chunk(con, self.store.as_ref(), &config.object_store.output_bucket, &config.queues, input, payload).await
+    }
+}
+
+async fn handle_chunk(
+    con: &mut redis_conn::Connection,
+    store: &dyn ObjectStore,
+    output_bucket: &str,
+    queues: &xml_common::config::Queues,
+    input: InputMsg,
+    json_str: &str,
+) -> Result<()> {
+    if cancel::is_cancelled(con, &input.job_id).await {
+        tracing::info!(reason = "job cancelled", "chunk.dropped");
+        chunk_state::record(con, &input.job_id, input.chunk_id, "cancelled", None).await;
+        return Ok(());
+    }
+    tracing::info!(s3_key = %input.s3_key, "chunk.received");
+    chunk_state::record(con, &input.job_id, input.chunk_id, "converting", None).await;
+    match process_job(store, output_bucket, &input).await {
+        Ok(converted) => {
+            chunk_state::record(con, &input.job_id, input.chunk_id, "converted", None).await;
+            let output_msg = XmlMsg {
+                job_id: input.job_id,
+                chunk_id: input.chunk_id,
+                xml_content: converted.xml,
+                mapper_version: MAPPER_VERSION.to_string(),
+                outputs: converted.outputs,
+                source: serde_json::from_str(json_str).ok(),
+                trace_context: telemetry::inject(),
             };
-            println!("Processing Job {} - Chunk {}", input.job_id, input.chunk_id);
-            match process_job(&s3_client, &input).await {
-                Ok(xml_output) => {
-                    let output_msg = XmlMsg {
-                        job_id: input.job_id,
-                        chunk_id: input.chunk_id,
-                        xml_content: xml_output,
-                        mapper_version: "1.0.0".to_string(),
-                    };
-                    let output_json = serde_json::to_string(&output_msg)?;
-                    let _: () = con.rpush("queue:xml_validation", output_json).await?;
-                },
-                Err(e) => { eprintln!("Failed to convert chunk: {}", e); }
-            }
+            let output_json = serde_json::to_string(&output_msg)?;
+            con.push(&queues.xml_validation, &output_json).await?;
+            metrics::PRODUCED.with_label_values(&[&queues.xml_validation]).inc();
+            tracing::info!(queue = %queues.xml_validation, xml_len = output_msg.xml_content.len(), "chunk.forwarded");
+            health::processed();
+        },
+        Err(e) => {
+            tracing::error!(error = %e, "chunk.failed");
+            metrics::FAILED.with_label_values(&[&queues.csv_processing]).inc();
+            chunk_state::record(con, &input.job_id, input.chunk_id, "failed", Some(&format!("conversion: {}", e))).await;
+            let failed_msg = FailedMsg {
+                job_id: input.job_id,
+                chunk_id: input.chunk_id,
+                xml_content: String::new(),
+                status: CONVERSION_FAILED,
+                mapper_version: MAPPER_VERSION.to_string(),
+                findings: vec![serde_json::json!({ "rule": "conversion", "severity": "error", "message": format!("{:#}", e) })],
+                source: serde_json::from_str(json_str).ok(),
+                trace_context: telemetry::inject(),
+            };
+            con.push(&queues.db_persistence, &serde_json::to_string(&failed_msg)?).await?;
+            metrics::PRODUCED.with_label_values(&[&queues.db_persistence]).inc();
+            tracing::info!(queue = %queues.db_persistence, status = CONVERSION_FAILED, "chunk.forwarded");
+            health::processed();
         }
     }
+    Ok(())
 }
 
-async fn process_job(s3: &S3Client, input: &InputMsg) -> Result<String> {
-    let obj = s3.get_object().bucket(&input.s3_bucket).key(&input.s3_key).send().await.context("Failed S3 download")?;
-    let data = obj.body.collect().await?.into_bytes();
-    let csv_str = String::from_utf8(data.to_vec())?;
-    let xml_str = convert_to_xml(csv_str, &input.job_id, input.chunk_id)?;
-    Ok(xml_str)
-}
\ No newline at end of file
+async fn process_job(store: &dyn ObjectStore, output_bucket: &str, input: &InputMsg) -> Result<ConvertedChunk> {
+    if let Some(requested) = input.mapper_version.as_deref() {
+        if requested != MAPPER_VERSION {
+            bail!("replay asked for mapper {} but this converter runs {}", requested, MAPPER_VERSION);
+        }
+    }
+    let download = metrics::S3_DOWNLOAD_SECONDS.start_timer();
+    let obj = store
+        .get(&input.s3_bucket, &input.s3_key)
+        .instrument(tracing::info_span!("s3.fetch", bucket = %input.s3_bucket, key = %input.s3_key))
+        .await?;
+    download.observe_duration();
+
+    let _conversion = metrics::CONVERSION_SECONDS.start_timer();
+    let format = formats::resolve(input.format.as_deref(), &input.s3_key)?;
+    let rows = tracing::info_span!("input.parse", format = ?format).in_scope(|| {
+        let data = compression::decompress(obj)?;
+        formats::read_rows(format, data)
+    })?;
+    let generated_at = resolve_generated_at(input)?;
+    let report = build_report(rows, &input.job_id, input.chunk_id, generated_at);
+    let xml = tracing::info_span!("xml.serialise").in_scope(|| convert_to_xml(&report, input.canonical))?;
+    let outputs = outputs::render(&report, &input.outputs)?;
+    if !output_bucket.is_empty() {
+        let key = format!("{}/chunk_{}.xml", input.job_id, input.chunk_id);
+        store
+            .put(output_bucket, &key, xml.clone().into_bytes())
+            .instrument(tracing::info_span!("s3.store", bucket = %output_bucket, key = %key))
+            .await?;
+    }
+    Ok(ConvertedChunk { xml, outputs })
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(generated_at: Option<&str>) -> InputMsg {
+        serde_json::from_value(serde_json::json!({
+            "job_id": "job-1", "s3_bucket": "b", "s3_key": "k.csv", "chunk_id": 2,
+            "generated_at": generated_at, "canonical": true,
+        }))
+        .unwrap()
+    }
+
+    fn rows() -> Vec<InputRow> {
+        let csv = "Ticker,Nome,Sector,PriceSMA_EUR,VolumeAvg,Price_1,Volume_1\nAAPL,Apple Inc.,Technology,180.50,1000,181.00,900\n";
+        formats::read_rows(formats::InputFormat::Csv, csv.as_bytes().to_vec()).unwrap()
+    }
+
+    fn convert(input: &InputMsg) -> String {
+        let report = build_report(rows(), &input.job_id, input.chunk_id, resolve_generated_at(input).unwrap());
+        convert_to_xml(&report, input.canonical).unwrap()
+    }
+
+    #[test]
+    fn same_input_converts_to_the_same_bytes() {
+        let input = input(Some("2026-01-01T10:00:00Z"));
+        let xml = convert(&input);
+        assert_eq!(xml, convert(&input));
+        assert!(xml.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<mr:MarketReport xmlns:mr="), "{}", xml);
+        assert!(xml.contains(r#"GeneratedAt="2026-01-01T10:00:00+00:00""#), "{}", xml);
+        assert_eq!(canonical::canonicalise(&xml).unwrap(), xml);
+        assert!(xml.contains("<ClosingPrice Currency=\"EUR\">181.00</ClosingPrice>"), "{}", xml);
+        assert!(xml.ends_with("</mr:MarketReport>\n"), "{}", xml);
+    }
+
+    #[test]
+    fn reports_match_the_published_schema() {
+        let schema = include_str!("../../schemas/market-report-v1.xsd");
+        let csv = "Ticker,Nome,Sector,PriceSMA_EUR,VolumeAvg,Price_1,Volume_1,Price_2\n\
+                   AAPL,Apple Inc.,Technology,180.50,1000,181.00,900,182.00\n\
+                   MSFT,Microsoft,Technology,410.00,2000,,,\n";
+        let rows = formats::read_rows(formats::InputFormat::Csv, csv.as_bytes().to_vec()).unwrap();
+        let generated_at = resolve_generated_at(&input(Some("2026-01-01T10:00:00Z"))).unwrap();
+        let report = build_report(rows, "job-1", 2, generated_at);
+        for canonical in [true, false] {
+            let xml = convert_to_xml(&report, canonical).unwrap();
+            if let Err(e) = xsd::validate(schema, &xml) {
+                panic!("{:#}\n{}", e, xml);
+            }
+        }
+
+        // The check does catch documents that stray from the schema
+        let xml = convert(&input(Some("2026-01-01T10:00:00Z")));
+        for broken in [
+            xml.replace("<Sector>", "<Industry>").replace("</Sector>", "</Industry>"),
+            xml.replace(" JobID=\"job-1\"", ""),
+            xml.replace("SchemaVersion=\"1.0\"", "SchemaVersion=\"2.0\""),
+            xml.replace("urn:tp3:market-report:v1", "urn:tp3:market-report:v2"),
+        ] {
+            assert!(xsd::validate(schema, &broken).is_err(), "{}", broken);
+        }
+    }
+
+    // Runs a chunk through the object store the way a laptop or CI run would:
+    // gzip CSV read from mem://, XML written back to it
+    #[tokio::test]
+    async fn converts_a_chunk_from_a_memory_store_end_to_end() {
+        use std::io::Write;
+
+        let store = storage::from_config(&config::ObjectStoreConfig { url: "mem://converter-e2e".to_string(), ..Default::default() }).await.unwrap();
+        let csv = "Ticker,Nome,Sector,PriceSMA_EUR,VolumeAvg,Price_1,Volume_1\nAAPL,Apple Inc.,Technology,180.50,1000,181.00,900\n";
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(csv.as_bytes()).unwrap();
+        storage::MemoryStore::named("converter-e2e").put("chunks", "job-1/chunk_2.csv.gz", gz.finish().unwrap()).await.unwrap();
+
+        let input: InputMsg = serde_json::from_value(serde_json::json!({
+            "job_id": "job-1", "s3_bucket": "chunks", "s3_key": "job-1/chunk_2.csv.gz", "chunk_id": 2,
+            "generated_at": "2026-01-01T10:00:00Z", "canonical": true, "outputs": ["json"],
+        }))
+        .unwrap();
+        let converted = process_job(store.as_ref(), "reports", &input).await.unwrap();
+        assert_eq!(converted.xml, convert(&input));
+        assert!(converted.outputs.contains_key("json"));
+        let written = store.get("reports", "job-1/chunk_2.xml").await.unwrap();
+        assert_eq!(String::from_utf8(written.data).unwrap(), converted.xml);
+
+        let missing = InputMsg { s3_key: "job-1/chunk_3.csv.gz".to_string(), ..input };
+        assert!(process_job(store.as_ref(), "", &missing).await.is_err());
+    }
+
+    #[test]
+    fn missing_volumes_stay_empty() {
+        let csv = "Ticker,Nome,Sector,PriceSMA_EUR,VolumeAvg,Price_1,Volume_1,Price_2,Volume_2\nAAPL,Apple Inc.,Technology,180.50,1000,181.00,,182.00,0\n";
+        let rows = formats::read_rows(formats::InputFormat::Csv, csv.as_bytes().to_vec()).unwrap();
+        let report = build_report(rows, "job-1", 2, "2026-01-01T10:00:00+00:00".to_string());
+        let days = &report.assets[0].daily_data.days;
+        assert_eq!((days[0].volume.as_str(), days[1].volume.as_str()), ("", "0"));
+    }
+
+    #[test]
+    fn rejects_an_invalid_generated_at() {
+        assert!(resolve_generated_at(&input(Some("yesterday"))).is_err());
+        assert!(resolve_generated_at(&input(None)).is_ok());
+    }
+}
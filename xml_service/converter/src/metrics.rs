@@ -0,0 +1,13 @@
+use prometheus::{register_histogram, Histogram};
+use std::sync::LazyLock;
+
+// The pipeline_* families and the /metrics server are shared (see
+// xml_common::metrics); these are the service's own.
+pub use xml_common::metrics::*;
+
+pub static S3_DOWNLOAD_SECONDS: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!("converter_s3_download_seconds", "Time to fetch a chunk from the object store").unwrap()
+});
+pub static CONVERSION_SECONDS: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!("converter_conversion_seconds", "Time to turn a fetched chunk into XML and extra outputs").unwrap()
+});
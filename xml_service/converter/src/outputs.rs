@@ -0,0 +1,176 @@
+use anyhow::{bail, Result};
+use base64ct::{Base64, Encoding};
+use prost::Message;
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::MarketReport;
+
+// Extra serialisations of the same MarketReport, requested per job through
+// InputMsg.outputs. XML is always produced and stays the validated form;
+// these ride along in XmlMsg.outputs keyed by format name.
+pub const JSON: &str = "json";
+pub const PROTOBUF: &str = "protobuf";
+
+pub fn render(report: &MarketReport, formats: &[String]) -> Result<HashMap<String, String>> {
+    let mut outputs = HashMap::new();
+    for format in formats {
+        match format.to_ascii_lowercase().as_str() {
+            JSON => {
+                outputs.insert(JSON.to_string(), serde_json::to_string(&Report::from(report))?);
+            }
+            PROTOBUF => {
+                let bytes = Report::from(report).encode_to_vec();
+                outputs.insert(PROTOBUF.to_string(), Base64::encode_string(&bytes));
+            }
+            "xml" => (),
+            other => bail!("Unsupported output format '{}'", other),
+        }
+    }
+    Ok(outputs)
+}
+
+// Flat layout of the report shared by both outputs: the JSON output uses the
+// field names, the protobuf one (package market_report) the field numbers.
+// Both are part of the contract: only ever append new fields. The XML structs
+// aren't serialised directly since their names are XML ones (@JobID, $value).
+#[derive(Clone, PartialEq, Message, Serialize)]
+struct Report {
+    #[prost(string, tag = "1")]
+    job_id: String,
+    #[prost(uint32, tag = "2")]
+    chunk_id: u32,
+    #[prost(string, tag = "3")]
+    generated_at: String,
+    #[prost(message, repeated, tag = "4")]
+    assets: Vec<ReportAsset>,
+    #[prost(string, tag = "5")]
+    schema_version: String,
+}
+
+#[derive(Clone, PartialEq, Message, Serialize)]
+struct ReportAsset {
+    #[prost(string, tag = "1")]
+    ticker: String,
+    #[prost(string, tag = "2")]
+    name: String,
+    #[prost(string, tag = "3")]
+    sector: String,
+    #[prost(string, tag = "4")]
+    market_cap: String,
+    #[prost(string, tag = "5")]
+    pe_ratio: String,
+    #[prost(string, tag = "6")]
+    eps: String,
+    #[prost(string, tag = "7")]
+    open_price: String,
+    #[prost(string, tag = "8")]
+    prev_close: String,
+    #[prost(string, tag = "9")]
+    beta: String,
+    #[prost(string, tag = "10")]
+    price_sma: String,
+    #[prost(string, tag = "11")]
+    avg_volume: String,
+    #[prost(message, repeated, tag = "12")]
+    days: Vec<ReportDay>,
+}
+
+#[derive(Clone, PartialEq, Message, Serialize)]
+struct ReportDay {
+    #[prost(uint32, tag = "1")]
+    index: u32,
+    #[prost(string, tag = "2")]
+    closing_price: String,
+    #[prost(string, tag = "3")]
+    currency: String,
+    #[prost(string, tag = "4")]
+    volume: String,
+}
+
+impl From<&MarketReport> for Report {
+    fn from(report: &MarketReport) -> Self {
+        Report {
+            job_id: report.job_id.clone(),
+            chunk_id: report.chunk_id,
+            generated_at: report.generated_at.clone(),
+            schema_version: report.schema_version.to_string(),
+            assets: report
+                .assets
+                .iter()
+                .map(|a| ReportAsset {
+                    ticker: a.ticker.clone(),
+                    name: a.identification.name.clone(),
+                    sector: a.identification.sector.clone(),
+                    market_cap: a.fundamental_data.MarketCap.clone(),
+                    pe_ratio: a.fundamental_data.PERatio.clone(),
+                    eps: a.fundamental_data.EPS.clone(),
+                    open_price: a.fundamental_data.OpenPrice.clone(),
+                    prev_close: a.fundamental_data.PrevClose.clone(),
+                    beta: a.fundamental_data.Beta.clone(),
+                    price_sma: a.indicators.price_sma.clone(),
+                    avg_volume: a.indicators.avg_volume.clone(),
+                    days: a
+                        .daily_data
+                        .days
+                        .iter()
+                        .map(|d| ReportDay {
+                            index: d.index as u32,
+                            closing_price: d.closing_price.value.clone(),
+                            currency: d.closing_price.currency.clone(),
+                            volume: d.volume.clone(),
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report() -> MarketReport {
+        let row = serde_json::from_value(serde_json::json!({
+            "Ticker": "AAPL", "Nome": "Apple Inc.", "Sector": "Technology",
+            "PriceSMA_EUR": "180.5", "VolumeAvg": "1000",
+            "Market Cap": "3T", "Previous Close": "179",
+            "Price_1": "181", "Volume_1": "900",
+        }))
+        .unwrap();
+        crate::build_report(vec![row], "job-1", 4, "2026-01-01T00:00:00+00:00".to_string())
+    }
+
+    #[test]
+    fn json_uses_plain_field_names() {
+        let outputs = render(&report(), &["JSON".to_string()]).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&outputs[JSON]).unwrap();
+        assert_eq!(json["job_id"], "job-1");
+        assert_eq!(json["chunk_id"], 4);
+        assert_eq!(json["schema_version"], "1.0");
+        let asset = &json["assets"][0];
+        assert_eq!(asset["ticker"], "AAPL");
+        assert_eq!(asset["market_cap"], "3T");
+        assert_eq!(asset["days"][0], serde_json::json!({ "index": 1, "closing_price": "181", "currency": "EUR", "volume": "900" }));
+        for xml_name in ["@JobID", "$value", "@xmlns:mr", "Asset"] {
+            assert!(!outputs[JSON].contains(xml_name), "{} in {}", xml_name, outputs[JSON]);
+        }
+    }
+
+    #[test]
+    fn protobuf_decodes_to_the_same_report() {
+        let outputs = render(&report(), &[PROTOBUF.to_string()]).unwrap();
+        let bytes = Base64::decode_vec(&outputs[PROTOBUF]).unwrap();
+        let decoded = Report::decode(bytes.as_slice()).unwrap();
+        assert_eq!(decoded, Report::from(&report()));
+        assert_eq!(decoded.assets[0].days[0].index, 1);
+    }
+
+    #[test]
+    fn xml_is_implicit_and_unknown_formats_fail() {
+        assert!(render(&report(), &["xml".to_string()]).unwrap().is_empty());
+        let err = render(&report(), &["yaml".to_string()]).unwrap_err();
+        assert_eq!(err.to_string(), "Unsupported output format 'yaml'");
+    }
+}
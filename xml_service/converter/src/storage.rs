@@ -0,0 +1,231 @@
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::Client as S3Client;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use crate::config::ObjectStoreConfig;
+
+// Raw object bytes plus whatever encoding hint the backend knows about
+// (S3 Content-Encoding header or a "content-encoding"/"compression" metadata entry)
+pub struct StoredObject {
+    pub data: Vec<u8>,
+    pub content_encoding: Option<String>,
+}
+
+// Where the converter reads chunk objects from. The backend is picked by the
+// scheme of object_store.url: s3:// (default), file:///some/dir or mem://name
+#[async_trait]
+pub trait ObjectStore: Send + Sync {
+    async fn get(&self, bucket: &str, key: &str) -> Result<StoredObject>;
+
+    async fn put(&self, bucket: &str, key: &str, data: Vec<u8>) -> Result<()>;
+
+    // Connectivity probe for /readyz
+    async fn check(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub struct S3Store {
+    client: S3Client,
+    // Bucket probed by `check`: object_store.health_bucket, or the last bucket read from
+    health_bucket: Mutex<Option<String>>,
+}
+
+impl S3Store {
+    // Credentials and region come from the usual AWS_* environment
+    pub async fn from_env(health_bucket: &str) -> Self {
+        let aws_config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
+        S3Store {
+            client: S3Client::new(&aws_config),
+            health_bucket: Mutex::new(Some(health_bucket.to_string()).filter(|b| !b.is_empty())),
+        }
+    }
+}
+
+#[async_trait]
+impl ObjectStore for S3Store {
+    async fn get(&self, bucket: &str, key: &str) -> Result<StoredObject> {
+        let obj = self.client.get_object().bucket(bucket).key(key).send().await.context("Failed S3 download")?;
+        let content_encoding = obj.content_encoding().map(str::to_string).or_else(|| {
+            obj.metadata().and_then(|m| m.get("content-encoding").or_else(|| m.get("compression")).cloned())
+        });
+        let data = obj.body.collect().await?.into_bytes();
+        self.health_bucket.lock().unwrap().get_or_insert_with(|| bucket.to_string());
+        Ok(StoredObject { data: data.to_vec(), content_encoding })
+    }
+
+    async fn put(&self, bucket: &str, key: &str, data: Vec<u8>) -> Result<()> {
+        self.client.put_object().bucket(bucket).key(key).body(ByteStream::from(data)).send().await.context("Failed S3 upload")?;
+        Ok(())
+    }
+
+    // Nothing to probe until a bucket is known
+    async fn check(&self) -> Result<()> {
+        let bucket = self.health_bucket.lock().unwrap().clone();
+        if let Some(bucket) = bucket {
+            self.client.head_bucket().bucket(&bucket).send().await.with_context(|| format!("S3 bucket {} unreachable", bucket))?;
+        }
+        Ok(())
+    }
+}
+
+// Objects live at <root>/<bucket>/<key>, mirroring the S3 layout on disk
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        LocalStore { root: root.into() }
+    }
+}
+
+#[async_trait]
+impl ObjectStore for LocalStore {
+    async fn get(&self, bucket: &str, key: &str) -> Result<StoredObject> {
+        let path = self.root.join(relative(bucket)?).join(relative(key)?);
+        let data = tokio::fs::read(&path).await.with_context(|| format!("Failed to read {}", path.display()))?;
+        Ok(StoredObject { data, content_encoding: None })
+    }
+
+    async fn put(&self, bucket: &str, key: &str, data: Vec<u8>) -> Result<()> {
+        let path = self.root.join(relative(bucket)?).join(relative(key)?);
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await.with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        tokio::fs::write(&path, data).await.with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(())
+    }
+
+    async fn check(&self) -> Result<()> {
+        tokio::fs::metadata(&self.root).await.with_context(|| format!("{} is not accessible", self.root.display()))?;
+        Ok(())
+    }
+}
+
+// Bucket and key come from queue messages: only plain relative paths are
+// accepted so they can't escape the store's root (../, /etc/passwd, C:\)
+fn relative(part: &str) -> Result<&Path> {
+    let path = Path::new(part);
+    if part.is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+        bail!("Invalid object path '{}'", part);
+    }
+    Ok(path)
+}
+
+// Objects kept in process memory, for running the converter end to end
+// without a network (integration tests, local runs). Stores opened with the
+// same mem://<name> share their objects, so a test can load input through
+// one handle and read what the converter wrote through another.
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    objects: Arc<Mutex<HashMap<ObjectKey, Vec<u8>>>>,
+}
+
+// (bucket, key)
+type ObjectKey = (String, String);
+
+impl MemoryStore {
+    pub fn named(name: &str) -> Self {
+        static STORES: OnceLock<Mutex<HashMap<String, MemoryStore>>> = OnceLock::new();
+        STORES.get_or_init(Default::default).lock().unwrap().entry(name.to_string()).or_default().clone()
+    }
+}
+
+#[async_trait]
+impl ObjectStore for MemoryStore {
+    async fn get(&self, bucket: &str, key: &str) -> Result<StoredObject> {
+        let data = self.objects
+            .lock()
+            .unwrap()
+            .get(&(bucket.to_string(), key.to_string()))
+            .cloned()
+            .with_context(|| format!("Object {}/{} not found in memory store", bucket, key))?;
+        Ok(StoredObject { data, content_encoding: None })
+    }
+
+    async fn put(&self, bucket: &str, key: &str, data: Vec<u8>) -> Result<()> {
+        self.objects.lock().unwrap().insert((bucket.to_string(), key.to_string()), data);
+        Ok(())
+    }
+}
+
+pub async fn from_config(config: &ObjectStoreConfig) -> Result<Box<dyn ObjectStore>> {
+    let (scheme, rest) = config.url.split_once("://").context("object_store.url must look like <scheme>://...")?;
+    match scheme {
+        "s3" => Ok(Box::new(S3Store::from_env(&config.health_bucket).await)),
+        "file" => {
+            if rest.is_empty() {
+                bail!("file:// object store needs a directory, e.g. file:///data/chunks");
+            }
+            Ok(Box::new(LocalStore::new(rest)))
+        }
+        "mem" => Ok(Box::new(MemoryStore::named(rest))),
+        other => bail!("Unsupported object store scheme '{}'", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store_with(key: &str, data: &[u8]) -> (LocalStore, PathBuf) {
+        let root = std::env::temp_dir().join(format!("converter-storage-{}-{}", std::process::id(), key.replace('/', "_")));
+        let path = root.join("bucket").join(key);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, data).unwrap();
+        (LocalStore::new(&root), root)
+    }
+
+    #[tokio::test]
+    async fn reads_objects_under_the_root() {
+        let (store, root) = store_with("job/chunk_1.json", b"{}");
+        assert_eq!(store.get("bucket", "job/chunk_1.json").await.unwrap().data, b"{}");
+        assert!(store.get("bucket", "job/missing.json").await.is_err());
+        store.check().await.unwrap();
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_paths_outside_the_root() {
+        let (store, root) = store_with("chunk.json", b"{}");
+        for (bucket, key) in [("bucket", "../bucket/chunk.json"), ("bucket", "/etc/passwd"), ("..", "bucket/chunk.json"), ("bucket", "")] {
+            let err = store.get(bucket, key).await.err().unwrap();
+            assert!(err.to_string().contains("Invalid object path"), "{}/{}: {}", bucket, key, err);
+        }
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn parses_the_store_url() {
+        let config = |url: &str| ObjectStoreConfig { url: url.to_string(), ..Default::default() };
+        assert!(from_config(&config("file:///data/chunks")).await.is_ok());
+        assert!(from_config(&config("file://")).await.is_err());
+        assert!(from_config(&config("mem://")).await.is_ok());
+        assert!(from_config(&config("chunks")).await.is_err());
+        assert!(from_config(&config("ftp://host/chunks")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn local_store_writes_under_the_root() {
+        let (store, root) = store_with("input.csv", b"");
+        store.put("bucket", "out/chunk_1.xml", b"<R/>".to_vec()).await.unwrap();
+        assert_eq!(std::fs::read(root.join("bucket/out/chunk_1.xml")).unwrap(), b"<R/>");
+        assert!(store.put("bucket", "../../escaped.xml", Vec::new()).await.is_err());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn memory_stores_with_the_same_name_share_objects() {
+        let loader = MemoryStore::named("storage-tests");
+        loader.put("bucket", "in.csv", b"a,b".to_vec()).await.unwrap();
+        let store = from_config(&ObjectStoreConfig { url: "mem://storage-tests".to_string(), ..Default::default() }).await.unwrap();
+        assert_eq!(store.get("bucket", "in.csv").await.unwrap().data, b"a,b");
+        assert!(store.get("other", "in.csv").await.is_err());
+        assert!(MemoryStore::named("storage-tests-other").get("bucket", "in.csv").await.is_err());
+    }
+}
@@ -0,0 +1,196 @@
+use anyhow::{bail, Context, Result};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::collections::HashMap;
+
+// Checks a document against the part of XML Schema that
+// schemas/market-report-v1.xsd uses: sequences with occurrence bounds,
+// required and fixed attributes, simple content and the xs: types listed in
+// `check_value`. Enough to hold the converter's output to the published
+// schema in tests without an XSD library; anything else in a schema is an
+// error rather than silently accepted.
+pub fn validate(schema: &str, xml: &str) -> Result<()> {
+    let schema = parse(schema).context("Unreadable schema")?;
+    let doc = parse(xml).context("Unreadable document")?;
+    let target_namespace = schema.attr("targetNamespace").unwrap_or_default();
+    let types: HashMap<&str, &Node> =
+        schema.children_named("complexType").filter_map(|t| Some((t.attr("name")?, t))).collect();
+
+    let decl = schema
+        .children_named("element")
+        .find(|e| e.attr("name") == Some(doc.local_name()))
+        .with_context(|| format!("No top-level element <{}> in the schema", doc.local_name()))?;
+    let xmlns = match doc.prefix() {
+        Some(prefix) => format!("xmlns:{}", prefix),
+        None => "xmlns".to_string(),
+    };
+    if doc.attr(&xmlns).unwrap_or_default() != target_namespace {
+        bail!("<{}> is not in namespace {}", doc.name, target_namespace);
+    }
+    check_element(decl, &doc, &types)
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    name: String,
+    attrs: Vec<(String, String)>,
+    children: Vec<Node>,
+    text: String,
+}
+
+impl Node {
+    fn prefix(&self) -> Option<&str> {
+        self.name.split_once(':').map(|(prefix, _)| prefix)
+    }
+
+    fn local_name(&self) -> &str {
+        self.name.split_once(':').map_or(self.name.as_str(), |(_, local)| local)
+    }
+
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str())
+    }
+
+    fn children_named<'a>(&'a self, local_name: &'a str) -> impl Iterator<Item = &'a Node> + 'a {
+        self.children.iter().filter(move |c| c.local_name() == local_name)
+    }
+
+    fn child(&self, local_name: &str) -> Option<&Node> {
+        self.children.iter().find(|c| c.local_name() == local_name)
+    }
+}
+
+fn parse(xml: &str) -> Result<Node> {
+    let mut reader = Reader::from_str(xml);
+    let mut stack: Vec<Node> = Vec::new();
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => stack.push(node(&e)?),
+            Event::Empty(e) => {
+                let node = node(&e)?;
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(node),
+                    None => return Ok(node),
+                }
+            }
+            Event::End(_) => {
+                let node = stack.pop().context("Unbalanced end tag")?;
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(node),
+                    None => return Ok(node),
+                }
+            }
+            Event::Text(t) => {
+                if let Some(current) = stack.last_mut() {
+                    current.text.push_str(&t.unescape()?);
+                }
+            }
+            Event::Eof => bail!("No root element"),
+            _ => (),
+        }
+    }
+}
+
+fn node(e: &BytesStart) -> Result<Node> {
+    let mut attrs = Vec::new();
+    for attr in e.attributes() {
+        let attr = attr?;
+        attrs.push((String::from_utf8(attr.key.as_ref().to_vec())?, attr.unescape_value()?.into_owned()));
+    }
+    Ok(Node { name: String::from_utf8(e.name().as_ref().to_vec())?, attrs, ..Default::default() })
+}
+
+fn check_element(decl: &Node, el: &Node, types: &HashMap<&str, &Node>) -> Result<()> {
+    let result = match (decl.child("complexType"), decl.attr("type")) {
+        (Some(complex), _) => check_complex(complex, el, types),
+        (None, Some(simple)) if simple.starts_with("xs:") => {
+            check_attributes(&[], el).and_then(|()| check_no_children(el)).and_then(|()| check_value(simple, &el.text))
+        }
+        (None, Some(named)) => {
+            let local = named.split_once(':').map_or(named, |(_, local)| local);
+            let complex = types.get(local).with_context(|| format!("Unknown type {}", named))?;
+            check_complex(complex, el, types)
+        }
+        (None, None) => bail!("Element {} has no type", decl.attr("name").unwrap_or_default()),
+    };
+    result.with_context(|| format!("in <{}>", el.name))
+}
+
+fn check_complex(complex: &Node, el: &Node, types: &HashMap<&str, &Node>) -> Result<()> {
+    if let Some(simple_content) = complex.child("simpleContent") {
+        let extension = simple_content.child("extension").context("simpleContent without extension")?;
+        check_attributes(&extension.children_named("attribute").collect::<Vec<_>>(), el)?;
+        check_no_children(el)?;
+        return check_value(extension.attr("base").unwrap_or_default(), &el.text);
+    }
+    check_attributes(&complex.children_named("attribute").collect::<Vec<_>>(), el)?;
+    let Some(sequence) = complex.child("sequence") else {
+        return check_no_children(el);
+    };
+
+    let mut children = el.children.iter().peekable();
+    for decl in sequence.children_named("element") {
+        let name = decl.attr("name").context("Unnamed element in a sequence")?;
+        let min: usize = decl.attr("minOccurs").unwrap_or("1").parse()?;
+        let max = match decl.attr("maxOccurs").unwrap_or("1") {
+            "unbounded" => usize::MAX,
+            n => n.parse()?,
+        };
+        let mut count = 0;
+        while count < max {
+            let Some(child) = children.next_if(|c| c.name == name) else { break };
+            check_element(decl, child, types)?;
+            count += 1;
+        }
+        if count < min {
+            bail!("Expected at least {} <{}>, found {}", min, name, count);
+        }
+    }
+    match children.next() {
+        Some(unexpected) => bail!("Unexpected <{}>", unexpected.name),
+        None => Ok(()),
+    }
+}
+
+// Namespace declarations and xsi:* are allowed anywhere
+fn check_attributes(decls: &[&Node], el: &Node) -> Result<()> {
+    for (name, value) in &el.attrs {
+        if name == "xmlns" || name.starts_with("xmlns:") || name.starts_with("xsi:") {
+            continue;
+        }
+        let decl = decls
+            .iter()
+            .find(|d| d.attr("name") == Some(name.as_str()))
+            .with_context(|| format!("Undeclared attribute {}", name))?;
+        if let Some(fixed) = decl.attr("fixed") {
+            if value != fixed {
+                bail!("Attribute {} must be {}, not {}", name, fixed, value);
+            }
+        }
+        check_value(decl.attr("type").unwrap_or("xs:string"), value).with_context(|| format!("in attribute {}", name))?;
+    }
+    for decl in decls.iter().filter(|d| d.attr("use") == Some("required")) {
+        let name = decl.attr("name").unwrap_or_default();
+        if el.attr(name).is_none() {
+            bail!("Missing required attribute {}", name);
+        }
+    }
+    Ok(())
+}
+
+fn check_no_children(el: &Node) -> Result<()> {
+    match el.children.first() {
+        Some(child) => bail!("Unexpected <{}> in simple content", child.name),
+        None => Ok(()),
+    }
+}
+
+fn check_value(simple_type: &str, value: &str) -> Result<()> {
+    match simple_type {
+        "xs:string" => Ok(()),
+        "xs:unsignedInt" => value.parse::<u32>().map(drop).with_context(|| format!("{:?} is not an xs:unsignedInt", value)),
+        "xs:unsignedByte" => value.parse::<u8>().map(drop).with_context(|| format!("{:?} is not an xs:unsignedByte", value)),
+        "xs:dateTime" => chrono::DateTime::parse_from_rfc3339(value).map(drop).with_context(|| format!("{:?} is not an xs:dateTime", value)),
+        other => bail!("Unsupported type {}", other),
+    }
+}
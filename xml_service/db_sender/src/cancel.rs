@@ -0,0 +1,132 @@
+use anyhow::{bail, Context, Result};
+use tokio_postgres::types::ToSql;
+use tokio_postgres::Client;
+
+use crate::completion::{self, JOB_CANCELLED};
+use crate::config::Config;
+use xml_common::redis_conn;
+
+// The job:{id}:cancelled marker and the checks around it are shared with the
+// other workers and the gRPC CancelJob RPC, see xml_common::cancel
+pub use xml_common::cancel::{is_cancelled, request, requested, Cancellation};
+
+// Deleting a cancelled job's rows races with chunks being inserted at the same
+// time. Both sides take the job's advisory lock: an insert holds it shared
+// until it commits and checks the cancel marker just before, the deletion
+// holds it exclusively. A chunk thus either commits before the deletion starts
+// (and is deleted with the rest) or sees the marker and rolls back.
+// chunk_sources are kept, so a cancelled job can still be replayed.
+const DELETE_ROWS: &str = "WITH xml AS (DELETE FROM xml_storage WHERE job_id = $1::text RETURNING 1),
+      outputs AS (DELETE FROM report_outputs WHERE job_id = $1::text RETURNING 1),
+      quarantined AS (DELETE FROM quarantine WHERE job_id = $1::text RETURNING 1)
+ SELECT (SELECT count(*) FROM xml), (SELECT count(*) FROM outputs), (SELECT count(*) FROM quarantined)";
+
+// Runs `statement` in a transaction on a connection of its own (a worker's)
+// and commits it unless the job was cancelled meanwhile; Ok(false) then
+pub async fn insert_unless_cancelled(
+    db_client: &Client,
+    con: &mut redis_conn::Connection,
+    job_id: &str,
+    statement: &str,
+    params: &[&(dyn ToSql + Sync)],
+) -> Result<bool, tokio_postgres::Error> {
+    db_client.batch_execute("BEGIN").await?;
+    let inserted = async {
+        db_client.execute("SELECT pg_advisory_xact_lock_shared(hashtext($1::text))", &[&job_id]).await?;
+        db_client.execute(statement, params).await
+    }
+    .await;
+    if let Err(e) = inserted {
+        let _ = db_client.batch_execute("ROLLBACK").await;
+        return Err(e);
+    }
+    if is_cancelled(con, job_id).await {
+        db_client.batch_execute("ROLLBACK").await?;
+        return Ok(false);
+    }
+    db_client.batch_execute("COMMIT").await?;
+    Ok(true)
+}
+
+// Called by the supervisor for every active job that has a cancellation marker
+pub async fn apply(
+    con: &mut redis_conn::Connection,
+    db_client: &Client,
+    settings: &completion::Settings,
+    job_id: &str,
+    cancellation: Cancellation,
+) -> Result<()> {
+    if cancellation.delete_rows {
+        // A session lock, as the supervisor's connection is shared with other
+        // background tasks and can't hold a transaction open. One statement so
+        // a failure leaves all of the job's rows in place.
+        db_client.execute("SELECT pg_advisory_lock(hashtext($1::text))", &[&job_id]).await?;
+        let deleted = db_client.query_one(DELETE_ROWS, &[&job_id]).await;
+        db_client.execute("SELECT pg_advisory_unlock(hashtext($1::text))", &[&job_id]).await?;
+        let row = deleted?;
+        let (chunks, outputs, quarantined): (i64, i64, i64) = (row.get(0), row.get(1), row.get(2));
+        tracing::info!(job_id, chunks, extra_outputs = outputs, quarantined, "job.rows_deleted");
+    }
+    let reason = if cancellation.reason.is_empty() { "cancelled by operator".to_string() } else { cancellation.reason };
+    completion::finalise(con, db_client, settings, job_id, Some(JOB_CANCELLED), Some(reason), Vec::new()).await
+}
+
+const USAGE: &str = "usage: db_sender cancel <job_id> [--delete-rows] [--reason <text>]";
+
+// job id, delete_rows, reason
+fn parse_args(args: &[String]) -> Result<(&str, bool, String)> {
+    let job_id = args.first().context(USAGE)?;
+    let mut delete_rows = false;
+    let mut reason = String::new();
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--delete-rows" => delete_rows = true,
+            "--reason" => reason = rest.next().context(USAGE)?.clone(),
+            _ => bail!(USAGE),
+        }
+    }
+    Ok((job_id, delete_rows, reason))
+}
+
+pub async fn run_cli(config: &Config, args: Vec<String>) -> Result<()> {
+    let (job_id, delete_rows, reason) = parse_args(&args)?;
+
+    let mut con = config.redis()?.connect().await?;
+
+    request(&mut con, job_id, &reason, delete_rows).await?;
+    println!(
+        "Job {} marked cancelled{}. Remaining chunks will be dropped and the job announced as {} on the next supervisor sweep.",
+        job_id,
+        if delete_rows { " (stored rows will be deleted)" } else { "" },
+        JOB_CANCELLED
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_the_cancel_command() {
+        let full = args(&["job-1", "--delete-rows", "--reason", "wrong file"]);
+        assert_eq!(parse_args(&full).unwrap(), ("job-1", true, "wrong file".to_string()));
+        assert_eq!(parse_args(&args(&["job-1"])).unwrap(), ("job-1", false, String::new()));
+        assert!(parse_args(&args(&[])).is_err());
+        assert!(parse_args(&args(&["job-1", "--reason"])).is_err());
+        assert!(parse_args(&args(&["job-1", "--force"])).is_err());
+    }
+
+    #[test]
+    fn deleting_rows_keeps_the_chunk_sources() {
+        for table in ["xml_storage", "report_outputs", "quarantine"] {
+            assert!(DELETE_ROWS.contains(&format!("DELETE FROM {} ", table)), "{}", table);
+        }
+        assert!(!DELETE_ROWS.contains("chunk_sources"));
+    }
+}
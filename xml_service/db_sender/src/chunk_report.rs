@@ -0,0 +1,87 @@
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use redis::AsyncCommands;
+use std::collections::{BTreeSet, HashMap};
+
+use crate::config::Config;
+
+// `db_sender chunks <job_id> [--stuck <secs>]`: prints where every chunk of a
+// job is and how long each stage took, from the state hashes the workers
+// keep in Redis (see chunk_state.rs). With --stuck only chunks sitting in an
+// unfinished state for longer than <secs> are shown.
+const USAGE: &str = "usage: db_sender chunks <job_id> [--stuck <secs>]";
+
+// (label, stage start field, stage end fields)
+const STAGES: &[(&str, &str, &[&str])] = &[
+    ("convert", "converting_at", &["converted_at"]),
+    ("validate", "validating_at", &["validated_at"]),
+    ("persist", "persisting_at", &["done_at", "failed_at"]),
+];
+
+pub async fn run_cli(config: &Config, args: Vec<String>) -> Result<()> {
+    let job_id = args.first().context(USAGE)?;
+    let stuck_after: Option<i64> = match (args.get(1).map(String::as_str), args.get(2)) {
+        (Some("--stuck"), Some(secs)) => Some(secs.parse().context("--stuck takes a number of seconds")?),
+        (None, _) => None,
+        _ => bail!(USAGE),
+    };
+
+    let mut con = config.redis()?.connect().await?;
+
+    let chunk_ids: BTreeSet<u32> = con.smembers(format!("job:{}:tracked_chunks", job_id)).await?;
+    if chunk_ids.is_empty() {
+        println!("No chunk state recorded for Job {} (unknown job or expired).", job_id);
+        return Ok(());
+    }
+
+    let now = Utc::now();
+    let mut by_state: HashMap<String, usize> = HashMap::new();
+    println!("{:>5}  {:<11}  {:>9}  {:>8}  {:>8}  {:>8}  error", "chunk", "state", "in state", "convert", "validate", "persist");
+    for chunk_id in chunk_ids {
+        let fields: HashMap<String, String> = con.hgetall(format!("job:{}:chunk:{}", job_id, chunk_id)).await?;
+        let state = fields.get("state").cloned().unwrap_or("unknown".to_string());
+        let in_state = at(&fields, "updated_at").map(|t| (now - t).num_seconds());
+        *by_state.entry(state.clone()).or_default() += 1;
+
+        let finished = matches!(state.as_str(), "done" | "failed" | "cancelled");
+        if let Some(limit) = stuck_after {
+            if finished || in_state.unwrap_or(0) <= limit {
+                continue;
+            }
+        }
+
+        let durations: Vec<String> = STAGES
+            .iter()
+            .map(|(_, start, ends)| {
+                let end = ends.iter().find_map(|e| at(&fields, e));
+                match (at(&fields, start), end) {
+                    (Some(s), Some(e)) => format!("{:.1}s", (e - s).num_milliseconds() as f64 / 1000.0),
+                    (Some(_), None) => "...".to_string(),
+                    _ => "-".to_string(),
+                }
+            })
+            .collect();
+        println!(
+            "{:>5}  {:<11}  {:>9}  {:>8}  {:>8}  {:>8}  {}",
+            chunk_id,
+            state,
+            if finished { "-".to_string() } else { in_state.map(|s| format!("{}s", s)).unwrap_or("?".to_string()) },
+            durations[0],
+            durations[1],
+            durations[2],
+            fields.get("error").map(String::as_str).unwrap_or("")
+        );
+    }
+
+    let mut summary: Vec<String> = by_state.into_iter().map(|(state, n)| format!("{} {}", n, state)).collect();
+    summary.sort();
+    println!("Job {}: {}", job_id, summary.join(", "));
+    Ok(())
+}
+
+fn at(fields: &HashMap<String, String>, name: &str) -> Option<DateTime<Utc>> {
+    fields
+        .get(name)
+        .and_then(|s| DateTime::parse_from_rfc3339(s).ok())
+        .map(|t| t.with_timezone(&Utc))
+}
@@ -0,0 +1,300 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use redis::AsyncCommands;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use tokio_postgres::Client;
+
+use crate::notify;
+use xml_common::redis_conn;
+
+// Job status vocabulary sent to webhook receivers (see WEBHOOKS.md).
+// Chunk statuses (OK / ERRO_CONVERSAO / ERRO_VALIDACAO / ERRO_PERSISTENCIA) stay internal to the pipeline.
+pub const JOB_COMPLETED: &str = "COMPLETED";
+pub const JOB_COMPLETED_WITH_ERRORS: &str = "COMPLETED_WITH_ERRORS";
+pub const JOB_TIMED_OUT: &str = "TIMED_OUT";
+pub const JOB_CANCELLED: &str = "CANCELLED";
+
+// Jobs that have started but not been finalised; scanned by the supervisor
+pub use xml_common::cancel::ACTIVE_JOBS_KEY;
+
+pub const PAYLOAD_VERSION: u32 = 1;
+
+// Same lifetime the chunker gives the other job:{id}:* keys
+const JOB_KEYS_TTL_SECS: i64 = 86400;
+
+#[derive(Serialize)]
+pub struct WebhookPayload {
+    pub version: u32,
+    pub job_id: String,
+    pub status: String,
+    pub chunks: ChunkStats,
+    pub assets_stored: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<String>,
+    pub finished_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_secs: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_report_url: Option<String>,
+    // TIMED_OUT: why the supervisor gave up and which chunks never arrived.
+    // CANCELLED: the reason given by the operator.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub missing_chunk_ids: Vec<u32>,
+    // Set when the job was re-run by `db_sender replay`; 1 for the first replay
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replay_run: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct ChunkStats {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<i64>,
+    pub processed: i64,
+    pub succeeded: i64,
+    pub failed: i64,
+    pub failed_by_stage: BTreeMap<String, i64>,
+}
+
+pub struct Settings {
+    pub targets: Vec<notify::Target>,
+    // e.g. https://dashboard/jobs/{job_id}/errors, only sent when chunks failed
+    pub error_report_url: Option<String>,
+}
+
+// Pipeline stage a failed chunk status belongs to
+fn failed_stage(chunk_status: &str) -> &'static str {
+    match chunk_status {
+        "ERRO_CONVERSAO" => "conversion",
+        "ERRO_VALIDACAO" => "validation",
+        "ERRO_PERSISTENCIA" => "persistence",
+        _ => "other",
+    }
+}
+
+// failed:<stage> counters of job:{id}:stats by stage
+fn failed_by_stage(stats: &BTreeMap<String, i64>) -> BTreeMap<String, i64> {
+    stats
+        .iter()
+        .filter_map(|(k, v)| k.strip_prefix("failed:").map(|stage| (stage.to_string(), *v)))
+        .collect()
+}
+
+pub fn count_assets(xml: &str) -> i64 {
+    let mut reader = Reader::from_str(xml);
+    let mut count = 0;
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) if e.local_name().as_ref() == b"Asset" => count += 1,
+            Ok(Event::Eof) | Err(_) => break,
+            _ => (),
+        }
+    }
+    count
+}
+
+pub fn job_key(job_id: &str, name: &str) -> String {
+    format!("job:{}:{}", job_id, name)
+}
+
+// Per-job state lives in Redis next to the chunker's processed/total keys:
+//   job:{id}:stats          hash with assets and failed:<stage> fields
+//   job:{id}:started_at     set by the chunker, or by the first chunk reaching db_sender
+//   job:{id}:last_progress  last time a chunk of the job was recorded
+//   job:{id}:chunks         set of chunk ids seen, to work out what's missing
+//   job:{id}:finalized      left behind after finalising so late chunks are ignored
+//   job:{id}:replay         replay run counter, kept across runs (see replay.rs)
+pub async fn check_completion(
+    con: &mut redis_conn::Connection,
+    db_client: &Client,
+    settings: &Settings,
+    job_id: &str,
+    chunk_id: u32,
+    chunk_status: &str,
+    assets_stored: i64,
+) {
+    if let Err(e) = record_chunk(con, db_client, settings, job_id, chunk_id, chunk_status, assets_stored).await {
+        tracing::error!(job_id, chunk_id, error = %e, "job.progress_failed");
+    }
+}
+
+async fn record_chunk(
+    con: &mut redis_conn::Connection,
+    db_client: &Client,
+    settings: &Settings,
+    job_id: &str,
+    chunk_id: u32,
+    chunk_status: &str,
+    assets_stored: i64,
+) -> Result<()> {
+    if con.exists(job_key(job_id, "finalized")).await? {
+        tracing::warn!(job_id, chunk_id, "job.late_chunk");
+        return Ok(());
+    }
+
+    let stats_key = job_key(job_id, "stats");
+    let started_key = job_key(job_id, "started_at");
+    let chunks_key = job_key(job_id, "chunks");
+    let progress_key = job_key(job_id, "last_progress");
+    let now = Utc::now().to_rfc3339();
+
+    let _: () = con.sadd(ACTIVE_JOBS_KEY, job_id).await?;
+    let _: bool = con.set_nx(&started_key, &now).await?;
+    let _: () = con.set_ex(&progress_key, &now, JOB_KEYS_TTL_SECS as u64).await?;
+    // A chunk delivered twice (a requeue after a slow forward, a retried push,
+    // a quarantine resubmission) is only counted the first time
+    let added: i64 = con.sadd(&chunks_key, chunk_id).await?;
+    if added == 0 {
+        tracing::warn!(job_id, chunk_id, "job.duplicate_chunk");
+        return Ok(());
+    }
+    // Stats first: with concurrent workers the chunk that brings `processed` to
+    // the total finalises from them, so every counted chunk must be in them
+    if chunk_status != "OK" {
+        let _: () = con.hincr(&stats_key, format!("failed:{}", failed_stage(chunk_status)), 1).await?;
+    }
+    if assets_stored > 0 {
+        let _: () = con.hincr(&stats_key, "assets", assets_stored).await?;
+    }
+    let processed: i64 = con.incr(job_key(job_id, "processed"), 1).await?;
+    for key in [&started_key, &stats_key, &chunks_key] {
+        let _: () = con.expire(key, JOB_KEYS_TTL_SECS).await?;
+    }
+
+    // Without a total the job can't complete here; the supervisor times it out instead
+    let total: Option<i64> = con.get(job_key(job_id, "total")).await?;
+    let Some(total) = total else {
+        tracing::info!(job_id, processed, "job.progress");
+        return Ok(());
+    };
+
+    tracing::info!(job_id, processed, total, "job.progress");
+
+    if processed >= total {
+        finalise(con, db_client, settings, job_id, None, None, Vec::new()).await?;
+    }
+    Ok(())
+}
+
+// Announces the job and clears its keys. `forced_status` is used by the supervisor
+// (TIMED_OUT, CANCELLED); otherwise the status follows from the failure counters.
+pub async fn finalise(
+    con: &mut redis_conn::Connection,
+    db_client: &Client,
+    settings: &Settings,
+    job_id: &str,
+    forced_status: Option<&str>,
+    reason: Option<String>,
+    missing_chunk_ids: Vec<u32>,
+) -> Result<()> {
+    let stats_key = job_key(job_id, "stats");
+    let started_key = job_key(job_id, "started_at");
+
+    let processed: Option<i64> = con.get(job_key(job_id, "processed")).await?;
+    let processed = processed.unwrap_or(0);
+    let total: Option<i64> = con.get(job_key(job_id, "total")).await?;
+    let stats: BTreeMap<String, i64> = con.hgetall(&stats_key).await?;
+    let started_at: Option<String> = con.get(&started_key).await?;
+    let replay_run: Option<i64> = con.get(job_key(job_id, "replay")).await?;
+
+    let failed_by_stage = failed_by_stage(&stats);
+    let failed: i64 = failed_by_stage.values().sum();
+    let final_status = forced_status.unwrap_or(if failed > 0 { JOB_COMPLETED_WITH_ERRORS } else { JOB_COMPLETED });
+
+    // Only one finaliser announces the job: the chunk that completes it and the
+    // supervisor timing it out or cancelling it can get here at the same time.
+    // The marker also makes record_chunk ignore late chunks.
+    let claimed: Option<String> = redis::cmd("SET")
+        .arg(job_key(job_id, "finalized"))
+        .arg(final_status)
+        .arg("NX")
+        .arg("EX")
+        .arg(JOB_KEYS_TTL_SECS)
+        .query_async(con)
+        .await?;
+    if claimed.is_none() {
+        tracing::info!(job_id, status = final_status, "job.already_finalised");
+        return Ok(());
+    }
+
+    let finished_at = Utc::now();
+    let duration_secs = started_at
+        .as_deref()
+        .and_then(|s| DateTime::parse_from_rfc3339(s).ok())
+        .map(|start| (finished_at - start.with_timezone(&Utc)).num_seconds());
+
+    tracing::info!(job_id, status = final_status, failed, processed, "job.finished");
+
+    let payload = WebhookPayload {
+        version: PAYLOAD_VERSION,
+        job_id: job_id.to_string(),
+        status: final_status.to_string(),
+        chunks: ChunkStats {
+            total,
+            processed,
+            succeeded: processed - failed,
+            failed,
+            failed_by_stage,
+        },
+        assets_stored: stats.get("assets").copied().unwrap_or(0),
+        started_at,
+        finished_at: finished_at.to_rfc3339(),
+        duration_secs,
+        error_report_url: settings
+            .error_report_url
+            .as_ref()
+            .filter(|_| failed > 0 || final_status == JOB_TIMED_OUT)
+            .map(|template| template.replace("{job_id}", job_id)),
+        reason,
+        missing_chunk_ids,
+        replay_run,
+    };
+
+    let idempotency_key = match replay_run {
+        Some(run) => format!("job:{}:replay:{}:completion", job_id, run),
+        None => format!("job:{}:completion", job_id),
+    };
+    notify::announce(con, db_client, &settings.targets, &idempotency_key, job_id, final_status, &payload).await;
+
+    let keys: Vec<String> = ["processed", "total", "stats", "started_at", "last_progress", "chunks", "tickers"]
+        .iter()
+        .map(|name| job_key(job_id, name))
+        .collect();
+    let _: () = con.del(&keys).await?;
+    let _: () = con.srem(ACTIVE_JOBS_KEY, job_id).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_chunks_are_counted_by_stage() {
+        assert_eq!(failed_stage("ERRO_CONVERSAO"), "conversion");
+        assert_eq!(failed_stage("ERRO_VALIDACAO"), "validation");
+        assert_eq!(failed_stage("ERRO_PERSISTENCIA"), "persistence");
+        assert_eq!(failed_stage("SOMETHING_ELSE"), "other");
+
+        let stats = BTreeMap::from([
+            ("assets".to_string(), 40),
+            ("failed:validation".to_string(), 2),
+            ("failed:persistence".to_string(), 1),
+        ]);
+        let by_stage = failed_by_stage(&stats);
+        assert_eq!(by_stage, BTreeMap::from([("persistence".to_string(), 1), ("validation".to_string(), 2)]));
+        assert!(failed_by_stage(&BTreeMap::from([("assets".to_string(), 3)])).is_empty());
+    }
+
+    #[test]
+    fn counts_assets_with_and_without_prefix() {
+        let xml = r#"<mr:MarketReport xmlns:mr="urn:x"><mr:Asset Ticker="A"/><mr:Asset Ticker="B"></mr:Asset></mr:MarketReport>"#;
+        assert_eq!(count_assets(xml), 2);
+        assert_eq!(count_assets("<MarketReport><Asset/><Assets/></MarketReport>"), 1);
+        assert_eq!(count_assets("not xml <"), 0);
+    }
+}
@@ -0,0 +1,152 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::notify::Target;
+use xml_common::redis_conn::Redis;
+use crate::replay::Policy;
+use xml_common::config::{DatabaseConfig, EnvKeys, LogConfig, MetricsConfig, Queues, RedisConfig, Settings, WorkerConfig};
+use xml_common::secret::Secret;
+
+// db_sender's configuration; see xml_common::config for how it's loaded.
+// It consumes queues.db_persistence and pushes to the other two when
+// replaying or resubmitting chunks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub redis: RedisConfig,
+    pub database: DatabaseConfig,
+    pub queues: Queues,
+    pub worker: WorkerConfig,
+    pub jobs: JobsConfig,
+    pub notify: NotifyConfig,
+    pub webhook: WebhookConfig,
+    pub replay: ReplayConfig,
+    pub metrics: MetricsConfig,
+    pub log: LogConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            redis: RedisConfig::default(),
+            database: DatabaseConfig::default(),
+            queues: Queues::default(),
+            worker: WorkerConfig::default(),
+            jobs: JobsConfig::default(),
+            notify: NotifyConfig::default(),
+            webhook: WebhookConfig::default(),
+            replay: ReplayConfig::default(),
+            metrics: MetricsConfig { port: 9103 },
+            log: LogConfig::default(),
+        }
+    }
+}
+
+// See supervisor.rs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobsConfig {
+    pub deadline_secs: u64,
+    pub stall_secs: u64,
+    pub supervisor_interval_secs: u64,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        JobsConfig { deadline_secs: 3600, stall_secs: 600, supervisor_interval_secs: 30 }
+    }
+}
+
+// See notify.rs for the target format
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotifyConfig {
+    pub targets: Vec<Target>,
+    // Shorthand for a single unfiltered http target when `targets` is empty
+    pub webhook_url: String,
+    pub error_report_url: String,
+}
+
+impl NotifyConfig {
+    pub fn targets(&self) -> Result<Vec<Target>> {
+        if !self.targets.is_empty() {
+            return Ok(self.targets.clone());
+        }
+        if self.webhook_url.is_empty() {
+            bail!("notify.targets or notify.webhook_url is required ({})", EnvKeys::of::<Config>().sources("notify.webhook_url"));
+        }
+        Ok(vec![Target::Http { url: self.webhook_url.clone(), statuses: Vec::new() }])
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookConfig {
+    // HMAC key for the X-Signature header; empty sends unsigned webhooks
+    pub secret: Secret,
+    pub max_attempts: i32,
+    pub timeout_secs: u64,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig { secret: Secret::default(), max_attempts: 8, timeout_secs: 10 }
+    }
+}
+
+// See replay.rs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplayConfig {
+    pub policy: String,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        ReplayConfig { policy: "supersede".to_string() }
+    }
+}
+
+impl Settings for Config {
+    const ENV_KEYS: &'static [(&'static str, &'static str)] = &[
+        ("jobs.deadline_secs", "JOB_DEADLINE_SECS"),
+        ("jobs.stall_secs", "JOB_STALL_SECS"),
+        ("jobs.supervisor_interval_secs", "JOB_SUPERVISOR_INTERVAL_SECS"),
+        ("notify.targets", "NOTIFY_TARGETS"),
+        ("notify.webhook_url", "WEBHOOK_URL"),
+        ("notify.error_report_url", "ERROR_REPORT_URL"),
+        ("webhook.secret", "WEBHOOK_SECRET"),
+        ("webhook.max_attempts", "WEBHOOK_MAX_ATTEMPTS"),
+        ("webhook.timeout_secs", "WEBHOOK_TIMEOUT_SECS"),
+        ("replay.policy", "REPLAY_POLICY"),
+    ];
+
+    // Only what every mode needs; the Redis and Postgres settings are checked by
+    // redis/database_url, since e.g. `db_sender chunks` never opens Postgres
+    fn validate(&self, keys: &EnvKeys) -> Result<()> {
+        self.queues.validate(keys)?;
+        self.worker.validate(keys)?;
+        keys.at_least("jobs.deadline_secs", self.jobs.deadline_secs, 1)?;
+        keys.at_least("jobs.stall_secs", self.jobs.stall_secs, 1)?;
+        keys.at_least("jobs.supervisor_interval_secs", self.jobs.supervisor_interval_secs, 1)?;
+        if self.webhook.max_attempts < 1 {
+            bail!("webhook.max_attempts must be at least 1, got {} ({})", self.webhook.max_attempts, keys.sources("webhook.max_attempts"));
+        }
+        keys.at_least("webhook.timeout_secs", self.webhook.timeout_secs, 1)?;
+        self.metrics.validate(keys)?;
+        Policy::parse(&self.replay.policy)?;
+        self.log.validate()
+    }
+}
+
+impl Config {
+    pub fn redis(&self) -> Result<Redis> {
+        self.redis.validate(&EnvKeys::of::<Config>())?;
+        Redis::open(&self.redis).context("Invalid redis settings")
+    }
+
+    pub fn database_url(&self) -> Result<Secret> {
+        EnvKeys::of::<Config>().required("database.url", self.database.url.expose())?;
+        Ok(Secret::new(crate::ensure_sslmode_require(self.database.url.expose())))
+    }
+}
@@ -1,11 +1,26 @@
-use anyhow::{Context, Result};
+mod cancel;
+mod chunk_report;
+mod completion;
+mod config;
+mod metrics;
+mod notify;
+mod outputs;
+mod quarantine;
+mod replay;
+mod supersede;
+mod supervisor;
+mod webhook;
+
+use anyhow::{bail, Context, Result};
 use native_tls::TlsConnector;
 use postgres_native_tls::MakeTlsConnector;
-use redis::AsyncCommands;
 use serde::{Deserialize, Serialize};
-use std::env;
+use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio_postgres::Client;
+use tracing::Instrument;
+use xml_common::{chunk_state, health, logging, redis_conn, shutdown, telemetry, worker};
 
 #[derive(Serialize, Deserialize, Debug)]
 struct PipelineMsg {
@@ -14,47 +29,67 @@ struct PipelineMsg {
     xml_content: String,
     status: String, 
     mapper_version: String,
-}
-
-#[derive(Serialize)]
-struct WebhookPayload {
-    job_id: String,
-    status: String,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    outputs: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    supersedes: Vec<supersede::Superseded>,
+    // Validation report from the validator, kept opaque here and stored as-is
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    findings: Vec<serde_json::Value>,
+    // Set when this chunk is a resubmission from the quarantine CLI
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    quarantine_id: Option<i64>,
+    // Converter input the chunk was built from, recorded for `db_sender replay`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<serde_json::Value>,
+    // W3C trace context (traceparent) of the sender, see telemetry.rs
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    trace_context: HashMap<String, String>,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    println!("DB Sender Service Starting...");
-    
-    let redis_host = env::var("REDIS_HOST").context("REDIS_HOST missing")?;
-    let redis_port = env::var("REDIS_PORT").unwrap_or("6379".to_string());
-    let redis_password = env::var("REDIS_PASSWORD").unwrap_or_default();
-    let db_url = env::var("DATABASE_URL").context("DATABASE_URL missing")?;
-    let webhook_url = env::var("WEBHOOK_URL").context("WEBHOOK_URL missing")?;
+    let (config, args) = xml_common::config::load::<config::Config>(std::env::args().skip(1).collect())?;
+    let config = Arc::new(config);
+    logging::init(env!("CARGO_PKG_NAME"), &config.log);
+    match args.first().map(String::as_str) {
+        Some("replay") => return replay::run_cli(&config, args[1..].to_vec()).await,
+        Some("quarantine") => return quarantine::run_cli(&config, args[1..].to_vec()).await,
+        Some("cancel") => return cancel::run_cli(&config, args[1..].to_vec()).await,
+        Some("chunks") => return chunk_report::run_cli(&config, args[1..].to_vec()).await,
+        Some(other) => bail!("Unknown command '{}', expected replay, quarantine, cancel or chunks", other),
+        None => (),
+    }
 
-    println!("Environment variables loaded successfully");
+    tracing::info!("service.starting");
 
-    let redis_url = if !redis_password.is_empty() {
-        format!("redis://:{}@{}:{}", redis_password, redis_host, redis_port)
-    } else {
-        format!("redis://{}:{}", redis_host, redis_port)
+    let redis_client = config.redis()?;
+    let db_url = config.database_url()?;
+    let completion_settings = Arc::new(completion::Settings {
+        targets: config.notify.targets()?,
+        error_report_url: Some(config.notify.error_report_url.clone()).filter(|s| !s.is_empty()),
+    });
+    let limits = supervisor::Limits {
+        deadline: Duration::from_secs(config.jobs.deadline_secs),
+        stall: Duration::from_secs(config.jobs.stall_secs),
+        interval: Duration::from_secs(config.jobs.supervisor_interval_secs),
     };
+    let replay_policy = replay::Policy::parse(&config.replay.policy)?;
+    let webhook_secret = Some(config.webhook.secret.clone()).filter(|s| !s.is_empty());
+    let concurrency = config.worker.concurrency;
 
-    println!("Connecting to Redis at {}:{}...", redis_host, redis_port);
-    let redis_client = redis::Client::open(redis_url.clone())
-        .context("Failed to create Redis client")?;
-    
+    tracing::debug!("config.loaded");
+
+    tracing::info!(redis = %redis_client.target(), "redis.connecting");
     // Retry logic for Redis connection
-    let mut redis_con = None;
     for attempt in 1..=10 {
-        match redis_client.get_tokio_connection().await {
-            Ok(conn) => {
-                redis_con = Some(conn);
-                println!("Connected to Redis successfully");
+        match redis_client.connect().await {
+            Ok(_) => {
+                tracing::info!("redis.connected");
                 break;
             }
             Err(e) => {
-                eprintln!("Redis connection attempt {}/10 failed: {}", attempt, e);
+                tracing::warn!(attempt, max_attempts = 10, error = %e, "redis.connect_failed");
                 if attempt < 10 {
                     tokio::time::sleep(Duration::from_secs(2)).await;
                 } else {
@@ -63,103 +98,256 @@ async fn main() -> Result<()> {
             }
         }
     }
-    let mut redis_con = redis_con.unwrap();
-
-    println!("Connecting to PostgreSQL...");
-    let db_url = ensure_sslmode_require(&db_url);
-
-    // Masked logging
-    let masked = if let Some(start) = db_url.find("://") {
-        let rest = &db_url[start + 3..];
-        if let Some(at) = rest.find('@') {
-            format!("{}://***@{}", &db_url[..start], &rest[at+1..])
-        } else {
-            db_url.clone()
-        }
-    } else {
-        "***".to_string()
-    };
-    println!("DB URL (masked): {}", masked);
 
-    let tls_connector = TlsConnector::builder()
-        .danger_accept_invalid_certs(true)  // Accept Supabase pooler's certificate
-        .build()
-        .context("Failed to build TLS connector")?;
-    let tls = MakeTlsConnector::new(tls_connector);
-
-    // Retry logic for PostgreSQL connection using tokio-postgres + native-tls (Supabase compatible)
-    let db_client: Client = loop {
-        match tokio_postgres::connect(&db_url, tls.clone()).await {
-            Ok((client, connection)) => {
-                tokio::spawn(async move {
-                    if let Err(e) = connection.await {
-                        eprintln!("Database connection error: {}", e);
-                    }
-                });
-                println!("Connected to PostgreSQL successfully");
-                break client;
-            }
-            Err(e) => {
-                eprintln!("PostgreSQL connection failed: {}. Retrying in 3 seconds...", e);
-                use std::error::Error;
-                if let Some(src) = e.source() {
-                    eprintln!("  Caused by: {}", src);
-                }
-                tokio::time::sleep(Duration::from_secs(3)).await;
-            }
-        }
-    };
+    tracing::info!("postgres.connecting");
 
+    tracing::info!(url = %db_url.masked(), "postgres.url");
+
+    let tls = postgres_tls()?;
+
+    // One connection for the background tasks and one per worker: a worker
+    // runs its insert in a transaction (see cancel::insert_unless_cancelled),
+    // so nothing else may send statements on its connection
+    let db_client = Arc::new(connect_postgres_retrying(db_url.expose(), &tls).await);
+    let mut db_pool = Vec::with_capacity(concurrency);
+    for _ in 0..concurrency {
+        db_pool.push(Arc::new(connect_postgres_retrying(db_url.expose(), &tls).await));
+    }
+
+    outputs::ensure_table(&db_client).await?;
+    quarantine::ensure_table(&db_client).await?;
+    webhook::ensure_table(&db_client).await?;
+    replay::ensure_table(&db_client).await?;
+
+    let webhook_timeout = Duration::from_secs(config.webhook.timeout_secs);
     let http_client = reqwest::Client::builder()
-        .timeout(Duration::from_secs(10))
+        .timeout(webhook_timeout)
         .build()
         .context("Failed to create HTTP client")?;
 
-    println!("Persister Service Started. Listening on 'queue:db_persistence'...");
+    if webhook_secret.is_none() {
+        tracing::warn!("webhook.unsigned");
+    }
+    tokio::spawn(webhook::run_delivery(db_client.clone(), webhook::Delivery {
+        http: http_client,
+        timeout: webhook_timeout,
+        secret: webhook_secret,
+        max_attempts: config.webhook.max_attempts,
+    }));
+    tokio::spawn(supervisor::run(redis_client.clone(), db_client.clone(), completion_settings.clone(), limits));
+    tokio::spawn(metrics::serve(config.metrics.port, health::liveness, health::readiness));
+    tokio::spawn(metrics::watch_queues(redis_client.clone(), vec![config.queues.db_persistence.clone()]));
+    let health_client = redis_client.clone();
+    tokio::spawn(health::watch("redis", move || health::redis_check(health_client.clone())));
+    let health_db = db_client.clone();
+    tokio::spawn(health::watch("postgres", move || {
+        let db_client = health_db.clone();
+        async move {
+            db_client.simple_query("SELECT 1").await?;
+            Ok(())
+        }
+    }));
+
+    // As in the converter and validator, the message in flight gets
+    // SHUTDOWN_TIMEOUT_SECS to finish and is then pushed back to the queue.
+    // The deadline only passes when Postgres or Redis stalls; a chunk whose
+    // row was already committed is then stored again on redelivery, but its
+    // job counts it once (see completion::record_chunk).
+    let shutdown = shutdown::Shutdown::listen(Duration::from_secs(config.worker.shutdown_timeout_secs));
+    tracing::info!(queue = %config.queues.db_persistence, concurrency, "service.started");
 
-    loop {
-        let result: Option<(String, String)> = redis_con.blpop("queue:db_persistence", 0.0).await?;
+    let senders = db_pool
+        .into_iter()
+        .map(|db_client| Sender {
+            db_client,
+            completion_settings: completion_settings.clone(),
+            replay_policy,
+            config: config.clone(),
+        })
+        .collect();
+    worker::run(&redis_client, &config.queues.db_persistence, config.worker.poll_timeout_secs, senders, &shutdown).await?;
+
+    tracing::info!("service.stopped");
+    telemetry::shutdown();
+    Ok(())
+}
+
+// One of WORKER_CONCURRENCY consumers, each with its own Redis and Postgres
+// connection. Ordering: with more than one worker, chunks of a job are stored
+// in any order, and a chunk that supersedes tickers (last-wins) may be stored
+// before the chunk it supersedes, leaving both rows; run db_sender with
+// WORKER_CONCURRENCY=1 if that policy matters. Completion doesn't depend on
+// order: each chunk is counted once and only the one that brings the count to
+// the job's total finalises it.
+struct Sender {
+    db_client: Arc<Client>,
+    completion_settings: Arc<completion::Settings>,
+    replay_policy: replay::Policy,
+    config: Arc<config::Config>,
+}
+
+impl worker::Handler for Sender {
+    type Message = PipelineMsg;
+
+    fn span(&self, worker: usize, msg: &PipelineMsg) -> tracing::Span {
+        let span = tracing::info_span!(
+            "chunk",
+            stage = "persist",
+            worker,
+            job_id = %msg.job_id,
+            chunk_id = msg.chunk_id,
+            mapper_version = %msg.mapper_version,
+        );
+        telemetry::set_parent(&span, &msg.trace_context);
+        span
+    }
+
+    // Nothing is forwarded from here, so a chunk is only requeued at the
+    // shutdown deadline
+    async fn handle(&self, redis_con: &mut redis_conn::Connection, msg: PipelineMsg, _payload: &str) -> Result<()> {
+        handle_chunk(redis_con, &self.db_client, &self.completion_settings, self.replay_policy, &self.config, msg).await;
+        Ok(())
+    }
+}
+
+async fn handle_chunk(
+    redis_con: &mut redis_conn::Connection,
+    db_client: &Client,
+    completion_settings: &completion::Settings,
+    replay_policy: replay::Policy,
+    config: &config::Config,
+    mut msg: PipelineMsg,
+) {
+    if cancel::is_cancelled(redis_con, &msg.job_id).await {
+        tracing::info!(reason = "job cancelled", "chunk.dropped");
+        chunk_state::record(redis_con, &msg.job_id, msg.chunk_id, "cancelled", None).await;
+        return;
+    }
+
+    tracing::info!(status = %msg.status, xml_len = msg.xml_content.len(), "chunk.received");
+    // A chunk the converter or validator rejected is only quarantined here and
+    // keeps the failed state and error its stage recorded
+    let rejected = msg.status != "OK";
+    if !rejected {
+        chunk_state::record(redis_con, &msg.job_id, msg.chunk_id, "persisting", None).await;
+    }
+    if let Err(e) = replay::store_source(db_client, &msg).await {
+        tracing::warn!(error = %e, "replay.source_record_failed");
+    }
+    let mut final_status = msg.status.clone();
+    let mut assets_stored = 0;
+    
+    if final_status == "OK" {
+        let insert_stmt = replay_policy.insert_statement(&msg);
+        let chunk_id_i32: i32 = msg.chunk_id as i32;
         
-        if let Some((_, json_str)) = result {
-            let msg: PipelineMsg = match serde_json::from_str(&json_str) {
-                Ok(m) => m,
-                Err(e) => {
-                    eprintln!("JSON Error: {}", e);
-                    continue;
+        let insert = metrics::INSERT_SECONDS.start_timer();
+        let inserted = cancel::insert_unless_cancelled(
+            db_client,
+            redis_con,
+            &msg.job_id,
+            insert_stmt,
+            &[&msg.job_id, &chunk_id_i32, &msg.xml_content, &msg.mapper_version],
+        ).instrument(tracing::info_span!("db.insert", table = "xml_storage")).await;
+        insert.observe_duration();
+
+        match inserted {
+            Ok(false) => {
+                tracing::info!(reason = "job cancelled", "chunk.dropped");
+                chunk_state::record(redis_con, &msg.job_id, msg.chunk_id, "cancelled", None).await;
+                return;
+            }
+            Ok(true) => {
+                assets_stored = completion::count_assets(&msg.xml_content);
+                tracing::info!(assets = assets_stored, "chunk.stored");
+                if replay_policy.supersedes(&msg) {
+                    match replay::supersede_outputs(db_client, &msg).await {
+                        Ok(0) => (),
+                        Ok(n) => tracing::info!(removed = n, "replay.outputs_superseded"),
+                        Err(e) => tracing::warn!(error = %e, "replay.outputs_supersede_failed"),
+                    }
+                }
+                if !msg.outputs.is_empty() {
+                    outputs::store(db_client, &msg).await;
                 }
-            };
-
-            let mut final_status = msg.status.clone();
-            
-            if final_status == "OK" {
-                println!("Persisting Job {} - Chunk {}", msg.job_id, msg.chunk_id);
-                println!("  job_id: {}, chunk_id: {}, xml_len: {}, mapper: {}", 
-                    msg.job_id, msg.chunk_id, msg.xml_content.len(), msg.mapper_version);
-                
-                // Use $3::text to tell postgres the parameter is text, then cast to xml
-                let insert_stmt = "INSERT INTO xml_storage (job_id, chunk_id, xml_documento, mapper_version) VALUES ($1::text, $2::int4, ($3::text)::xml, $4::text)";
-                let chunk_id_i32: i32 = msg.chunk_id as i32;
-                
-                match db_client.execute(
-                    insert_stmt,
-                    &[&msg.job_id, &chunk_id_i32, &msg.xml_content, &msg.mapper_version],
-                ).await {
-                    Ok(_) => {
-                        println!("Saved Chunk {} to DB.", msg.chunk_id);
-                    },
-                    Err(e) => {
-                        eprintln!("DB Insert Failed: {}", e);
-                        if let Some(db_err) = e.as_db_error() {
-                            eprintln!("  DB Error: {} - {}", db_err.code().code(), db_err.message());
-                        }
-                        final_status = "ERRO_PERSISTENCIA".to_string();
+                if let Some(id) = msg.quarantine_id {
+                    if let Err(e) = quarantine::resolve(db_client, id).await {
+                        tracing::warn!(quarantine_id = id, error = %e, "quarantine.resolve_failed");
                     }
                 }
-            } else {
-                println!("Skipping persistence for Job {} Chunk {} due to {}", msg.job_id, msg.chunk_id, final_status);
+                for superseded in &msg.supersedes {
+                    match supersede::apply(db_client, &msg.job_id, superseded).await {
+                        Ok(()) => tracing::info!(superseded_chunk_id = superseded.chunk_id, tickers = ?superseded.tickers, "duplicates.superseded"),
+                        Err(e) => tracing::warn!(superseded_chunk_id = superseded.chunk_id, error = %e, "duplicates.supersede_failed"),
+                    }
+                }
+            },
+            Err(e) => {
+                let db_error = e.as_db_error().map(|db_err| format!("{} - {}", db_err.code().code(), db_err.message()));
+                tracing::error!(error = %e, db_error, "chunk.failed");
+                final_status = "ERRO_PERSISTENCIA".to_string();
+                metrics::FAILED.with_label_values(&[&config.queues.db_persistence]).inc();
+                if let Err(e) = supersede::release(redis_con, &msg.job_id, msg.chunk_id, &msg.xml_content, &msg.supersedes).await {
+                    tracing::warn!(error = %e, "duplicates.release_failed");
+                }
+                // Quarantined below with the database error, so the validated document isn't lost
+                msg.status = final_status.clone();
+                msg.findings.push(serde_json::json!({ "rule": "persistence", "severity": "error", "message": db_error.unwrap_or_else(|| e.to_string()) }));
             }
+        }
+    }
 
-            check_completion(&mut redis_con, &http_client, &msg.job_id, &webhook_url, &final_status).await;
+    if final_status != "OK" {
+        match quarantine::store(db_client, &msg).await {
+            Ok(id) => tracing::warn!(status = %final_status, quarantine_id = id, "chunk.quarantined"),
+            Err(e) => tracing::error!(status = %final_status, error = %e, "quarantine.store_failed"),
+        }
+    }
+
+    if final_status == "OK" {
+        chunk_state::record(redis_con, &msg.job_id, msg.chunk_id, "done", None).await;
+    } else if !rejected {
+        chunk_state::record(redis_con, &msg.job_id, msg.chunk_id, "failed", Some(&final_status)).await;
+    }
+    health::processed();
+
+    completion::check_completion(redis_con, db_client, completion_settings, &msg.job_id, msg.chunk_id, &final_status, assets_stored).await;
+}
+
+// tokio-postgres + native-tls, Supabase compatible
+fn postgres_tls() -> Result<MakeTlsConnector> {
+    let tls_connector = TlsConnector::builder()
+        .danger_accept_invalid_certs(true)  // Accept Supabase pooler's certificate
+        .build()
+        .context("Failed to build TLS connector")?;
+    Ok(MakeTlsConnector::new(tls_connector))
+}
+
+// One connection, driven in the background; the CLI commands use it as is
+async fn connect_postgres(db_url: &str, tls: &MakeTlsConnector) -> Result<Client, tokio_postgres::Error> {
+    let (client, connection) = tokio_postgres::connect(db_url, tls.clone()).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            tracing::error!(error = %e, "postgres.connection_failed");
+        }
+    });
+    Ok(client)
+}
+
+// Retries until Postgres answers
+async fn connect_postgres_retrying(db_url: &str, tls: &MakeTlsConnector) -> Client {
+    loop {
+        match connect_postgres(db_url, tls).await {
+            Ok(client) => {
+                tracing::info!("postgres.connected");
+                return client;
+            }
+            Err(e) => {
+                use std::error::Error;
+                let cause = e.source().map(|src| src.to_string());
+                tracing::warn!(error = %e, cause, retry_in_secs = 3, "postgres.connect_failed");
+                tokio::time::sleep(Duration::from_secs(3)).await;
+            }
         }
     }
 }
@@ -173,53 +361,3 @@ fn ensure_sslmode_require(url: &str) -> String {
         format!("{url}?sslmode=require")
     }
 }
-
-async fn check_completion(
-    con: &mut redis::aio::Connection, 
-    http: &reqwest::Client,
-    job_id: &str, 
-    webhook_url: &str,
-    chunk_status: &str
-) {
-    let processed_key = format!("job:{}:processed", job_id);
-    let total_key = format!("job:{}:total", job_id);
-    let error_key = format!("job:{}:errors", job_id);
-
-    let processed: i32 = match con.incr(&processed_key, 1).await {
-        Ok(v) => v,
-        Err(_) => return,
-    };
-
-    if chunk_status != "OK" {
-        let _: () = con.incr(&error_key, 1).await.unwrap_or(());
-    }
-
-    let total_str: Option<String> = con.get(&total_key).await.unwrap_or(None);
-    let total: i32 = total_str.unwrap_or("999999".to_string()).parse().unwrap_or(999999);
-
-    println!("Progress Job {}: {} / {}", job_id, processed, total);
-
-    if processed >= total {
-        let error_count: i32 = con.get(&error_key).await.unwrap_or(0);
-        
-        let final_status = if error_count > 0 {
-            "CONCLUIDO_COM_ERROS".to_string()
-        } else {
-            "OK".to_string()
-        };
-
-        println!("JOB {} FINISHED! Status: {}. Errors: {}", job_id, final_status, error_count);
-        
-        let payload = WebhookPayload {
-            job_id: job_id.to_string(),
-            status: final_status,
-        };
-
-        match http.post(webhook_url).json(&payload).send().await {
-            Ok(_) => println!("Webhook sent successfully."),
-            Err(e) => eprintln!("Failed to call webhook: {}", e),
-        }
-        
-        let _: () = con.del(&[processed_key, total_key, error_key]).await.unwrap_or(());
-    }
-}
\ No newline at end of file
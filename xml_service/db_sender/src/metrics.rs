@@ -0,0 +1,10 @@
+use prometheus::{register_histogram, Histogram};
+use std::sync::LazyLock;
+
+// The pipeline_* families and the /metrics server are shared (see
+// xml_common::metrics); these are the service's own.
+pub use xml_common::metrics::*;
+
+pub static INSERT_SECONDS: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!("db_sender_insert_seconds", "Time to insert a chunk into xml_storage").unwrap()
+});
@@ -0,0 +1,152 @@
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use tokio_postgres::Client;
+
+use xml_common::redis_conn;
+use crate::webhook;
+
+// Where job completions are announced. Configured as notify.targets, a JSON
+// list in NOTIFY_TARGETS or an array of tables in the config file, e.g.
+//   [{"type": "http", "url": "https://cleanup/hook", "statuses": ["COMPLETED"]},
+//    {"type": "redis", "channel": "jobs:completed"},
+//    {"type": "postgres", "channel": "job_completed"}]
+// `statuses` filters on the final job status; leave it out to receive all.
+// Without targets, notify.webhook_url (WEBHOOK_URL) alone is used as a single
+// unfiltered http target.
+//
+// Only http targets go through the retried outbox; Redis pub/sub and
+// Postgres NOTIFY are live signals and are published once. NOTIFY payloads
+// must stay under 8000 bytes, which a job with many missing chunks exceeds,
+// so postgres targets only get the job id and status.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Target {
+    Http {
+        url: String,
+        #[serde(default)]
+        statuses: Vec<String>,
+    },
+    Redis {
+        channel: String,
+        #[serde(default)]
+        statuses: Vec<String>,
+    },
+    Postgres {
+        channel: String,
+        #[serde(default)]
+        statuses: Vec<String>,
+    },
+}
+
+impl Target {
+    fn statuses(&self) -> &[String] {
+        match self {
+            Target::Http { statuses, .. } | Target::Redis { statuses, .. } | Target::Postgres { statuses, .. } => statuses,
+        }
+    }
+
+    fn accepts(&self, status: &str) -> bool {
+        self.statuses().is_empty() || self.statuses().iter().any(|s| s == status)
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Target::Http { url, .. } => format!("http {}", url),
+            Target::Redis { channel, .. } => format!("redis channel {}", channel),
+            Target::Postgres { channel, .. } => format!("postgres channel {}", channel),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Signal<'a> {
+    job_id: &'a str,
+    status: &'a str,
+}
+
+pub async fn announce(
+    con: &mut redis_conn::Connection,
+    db_client: &Client,
+    targets: &[Target],
+    idempotency_key: &str,
+    job_id: &str,
+    status: &str,
+    payload: &impl Serialize,
+) {
+    let body = match serde_json::to_string(payload) {
+        Ok(b) => b,
+        Err(e) => {
+            tracing::error!(idempotency_key, error = %e, "notify.serialise_failed");
+            return;
+        }
+    };
+
+    for target in targets.iter().filter(|t| t.accepts(status)) {
+        let result = match target {
+            Target::Http { url, .. } => webhook::enqueue(db_client, url, idempotency_key, payload).await,
+            Target::Redis { channel, .. } => con.publish::<_, _, ()>(channel, &body).await.map_err(Into::into),
+            Target::Postgres { channel, .. } => db_client
+                .execute("SELECT pg_notify($1::text, $2::text)", &[channel, &signal(job_id, status)])
+                .await
+                .map(|_| ())
+                .map_err(Into::into),
+        };
+        match result {
+            Ok(()) => tracing::info!(idempotency_key, target = %target.describe(), "notify.sent"),
+            Err(e) => tracing::error!(idempotency_key, target = %target.describe(), error = %e, "notify.failed"),
+        }
+    }
+}
+
+fn signal(job_id: &str, status: &str) -> String {
+    serde_json::to_string(&Signal { job_id, status }).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::NotifyConfig;
+
+    #[test]
+    fn parses_targets_from_json() {
+        let targets: Vec<Target> = serde_json::from_str(
+            r#"[{"type": "http", "url": "https://cleanup/hook", "statuses": ["COMPLETED"]},
+                {"type": "redis", "channel": "jobs:completed"},
+                {"type": "postgres", "channel": "job_completed"}]"#,
+        )
+        .unwrap();
+        let described: Vec<String> = targets.iter().map(Target::describe).collect();
+        assert_eq!(described, ["http https://cleanup/hook", "redis channel jobs:completed", "postgres channel job_completed"]);
+        assert_eq!(targets[0].statuses(), ["COMPLETED"]);
+        assert!(targets[1].statuses().is_empty());
+    }
+
+    #[test]
+    fn rejects_unknown_target_types() {
+        assert!(serde_json::from_str::<Vec<Target>>(r#"[{"type": "sqs", "url": "q"}]"#).is_err());
+        assert!(serde_json::from_str::<Vec<Target>>(r#"[{"type": "http"}]"#).is_err());
+    }
+
+    #[test]
+    fn webhook_url_alone_is_an_unfiltered_http_target() {
+        let config = NotifyConfig { webhook_url: "https://audit/hook".to_string(), ..Default::default() };
+        let targets = config.targets().unwrap();
+        assert!(matches!(&targets[..], [Target::Http { url, statuses }] if url == "https://audit/hook" && statuses.is_empty()));
+        assert!(NotifyConfig::default().targets().is_err());
+    }
+
+    #[test]
+    fn status_filters_pick_the_targets() {
+        let filtered = Target::Http { url: "https://cleanup/hook".to_string(), statuses: vec!["COMPLETED".to_string(), "CANCELLED".to_string()] };
+        assert!(filtered.accepts("COMPLETED"));
+        assert!(filtered.accepts("CANCELLED"));
+        assert!(!filtered.accepts("COMPLETED_WITH_ERRORS"));
+        let unfiltered = Target::Postgres { channel: "job_completed".to_string(), statuses: Vec::new() };
+        assert!(unfiltered.accepts("TIMED_OUT"));
+    }
+
+    #[test]
+    fn postgres_signals_carry_only_the_job_and_status() {
+        assert_eq!(signal("job-1", "COMPLETED"), r#"{"job_id":"job-1","status":"COMPLETED"}"#);
+    }
+}
@@ -0,0 +1,36 @@
+use anyhow::{Context, Result};
+use tokio_postgres::Client;
+
+use crate::PipelineMsg;
+
+// Side table for the optional JSON/protobuf serialisations a job asks the
+// converter for (`"outputs": ["json", "protobuf"]`); XML stays in xml_storage
+pub async fn ensure_table(db_client: &Client) -> Result<()> {
+    db_client.batch_execute(
+        "CREATE TABLE IF NOT EXISTS report_outputs (
+            job_id text NOT NULL,
+            chunk_id int4 NOT NULL,
+            mapper_version text NOT NULL,
+            json_documento jsonb,
+            protobuf_documento bytea,
+            created_at timestamptz NOT NULL DEFAULT now()
+        )"
+    ).await.context("Failed to create report_outputs table")?;
+    Ok(())
+}
+
+// Failures here are logged but don't fail the chunk: the XML row is the canonical copy
+pub async fn store(db_client: &Client, msg: &PipelineMsg) {
+    let insert_stmt = "INSERT INTO report_outputs (job_id, chunk_id, mapper_version, json_documento, protobuf_documento) VALUES ($1::text, $2::int4, $3::text, ($4::text)::jsonb, decode($5::text, 'base64'))";
+    let chunk_id_i32: i32 = msg.chunk_id as i32;
+    let json = msg.outputs.get("json");
+    let protobuf = msg.outputs.get("protobuf");
+
+    match db_client.execute(
+        insert_stmt,
+        &[&msg.job_id, &chunk_id_i32, &msg.mapper_version, &json, &protobuf],
+    ).await {
+        Ok(_) => tracing::info!(formats = %msg.outputs.keys().cloned().collect::<Vec<_>>().join(", "), "chunk.outputs_stored"),
+        Err(e) => tracing::warn!(error = %e, "chunk.outputs_store_failed"),
+    }
+}
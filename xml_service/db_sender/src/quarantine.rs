@@ -0,0 +1,202 @@
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use tokio_postgres::Client;
+
+use crate::config::Config;
+use crate::PipelineMsg;
+
+// Chunks that failed validation are kept here with their findings instead of
+// being dropped. Operators go through `db_sender quarantine ...` to look at
+// them and push them back into queues.xml_validation once fixed. Chunks the
+// converter couldn't convert (ERRO_CONVERSAO) are kept too, with no document;
+// they can only be redone with `db_sender replay`. Chunks whose insert failed
+// (ERRO_PERSISTENCIA) keep their validated document and the database error,
+// and can be resubmitted as they are once the database is fixed.
+//   state: quarantined -> resubmitted -> resolved | quarantined (failed again)
+//                      -> discarded
+pub async fn ensure_table(db_client: &Client) -> Result<()> {
+    db_client.batch_execute(
+        "CREATE TABLE IF NOT EXISTS quarantine (
+            id bigserial PRIMARY KEY,
+            job_id text NOT NULL,
+            chunk_id int4 NOT NULL,
+            mapper_version text NOT NULL,
+            xml_documento text NOT NULL,
+            status text NOT NULL,
+            report jsonb NOT NULL DEFAULT '[]'::jsonb,
+            state text NOT NULL DEFAULT 'quarantined',
+            created_at timestamptz NOT NULL DEFAULT now(),
+            updated_at timestamptz NOT NULL DEFAULT now()
+        )"
+    ).await.context("Failed to create quarantine table")?;
+    Ok(())
+}
+
+// A resubmitted chunk that fails again updates its original row
+pub async fn store(db_client: &Client, msg: &PipelineMsg) -> Result<i64> {
+    let report = serde_json::to_string(&msg.findings)?;
+    let chunk_id_i32: i32 = msg.chunk_id as i32;
+    let row = match msg.quarantine_id {
+        Some(id) => db_client.query_one(
+            "UPDATE quarantine SET xml_documento = $2::text, status = $3::text, report = ($4::text)::jsonb, mapper_version = $5::text, state = 'quarantined', updated_at = now() WHERE id = $1 RETURNING id",
+            &[&id, &msg.xml_content, &msg.status, &report, &msg.mapper_version],
+        ).await?,
+        None => db_client.query_one(
+            "INSERT INTO quarantine (job_id, chunk_id, mapper_version, xml_documento, status, report) VALUES ($1::text, $2::int4, $3::text, $4::text, $5::text, ($6::text)::jsonb) RETURNING id",
+            &[&msg.job_id, &chunk_id_i32, &msg.mapper_version, &msg.xml_content, &msg.status, &report],
+        ).await?,
+    };
+    Ok(row.get(0))
+}
+
+pub async fn resolve(db_client: &Client, id: i64) -> Result<()> {
+    db_client.execute("UPDATE quarantine SET state = 'resolved', updated_at = now() WHERE id = $1", &[&id]).await?;
+    Ok(())
+}
+
+// Message pushed back to the validator; mirrors the validator's XmlMsg
+#[derive(Serialize)]
+struct ResubmitMsg {
+    job_id: String,
+    chunk_id: u32,
+    xml_content: String,
+    mapper_version: String,
+    quarantine_id: i64,
+}
+
+// The fixed document when one is given, else the stored one; a chunk that
+// failed conversion has none stored
+fn resubmitted_document(id: i64, status: &str, stored: String, fixed: Option<String>) -> Result<String> {
+    match fixed {
+        Some(xml) => Ok(xml),
+        None if status == "ERRO_CONVERSAO" => bail!("Chunk {} failed conversion and has no document; pass a fixed.xml or replay its job", id),
+        None => Ok(stored),
+    }
+}
+
+const USAGE: &str = "usage: db_sender quarantine <list [job_id] | show <id> | resubmit <id> [fixed.xml] | discard <id>>";
+
+pub async fn run_cli(config: &Config, args: Vec<String>) -> Result<()> {
+    let db_url = config.database_url()?;
+    let db_client = crate::connect_postgres(db_url.expose(), &crate::postgres_tls()?)
+        .await
+        .context("Failed to connect to PostgreSQL")?;
+    ensure_table(&db_client).await?;
+
+    let id_arg = |i: usize| -> Result<i64> {
+        args.get(i).context(USAGE)?.parse().context("id must be a number")
+    };
+
+    match args.first().map(String::as_str) {
+        Some("list") => {
+            let rows = match args.get(1) {
+                Some(job_id) => db_client.query(
+                    "SELECT id, job_id, chunk_id, status, state, jsonb_array_length(report), updated_at::text FROM quarantine WHERE job_id = $1::text ORDER BY id",
+                    &[job_id],
+                ).await?,
+                None => db_client.query(
+                    "SELECT id, job_id, chunk_id, status, state, jsonb_array_length(report), updated_at::text FROM quarantine WHERE state <> 'resolved' AND state <> 'discarded' ORDER BY id",
+                    &[],
+                ).await?,
+            };
+            println!("{:>6}  {:<36}  {:>5}  {:<16}  {:<12}  {:>8}  updated", "id", "job_id", "chunk", "status", "state", "findings");
+            for row in rows {
+                let (id, job_id, chunk_id, status, state, findings, updated): (i64, String, i32, String, String, i32, String) =
+                    (row.get(0), row.get(1), row.get(2), row.get(3), row.get(4), row.get(5), row.get(6));
+                println!("{:>6}  {:<36}  {:>5}  {:<16}  {:<12}  {:>8}  {}", id, job_id, chunk_id, status, state, findings, updated);
+            }
+        }
+        Some("show") => {
+            let id = id_arg(1)?;
+            let row = db_client.query_opt(
+                "SELECT job_id, chunk_id, mapper_version, status, state, jsonb_pretty(report), xml_documento FROM quarantine WHERE id = $1",
+                &[&id],
+            ).await?.with_context(|| format!("No quarantined chunk with id {}", id))?;
+            let (job_id, chunk_id, mapper, status, state, report, xml): (String, i32, String, String, String, String, String) =
+                (row.get(0), row.get(1), row.get(2), row.get(3), row.get(4), row.get(5), row.get(6));
+            println!("Job {} Chunk {} (mapper {}) status={} state={}", job_id, chunk_id, mapper, status, state);
+            println!("Validation report:\n{}", report);
+            println!("Document:\n{}", xml);
+        }
+        Some("resubmit") => {
+            let id = id_arg(1)?;
+            let row = db_client.query_opt(
+                "SELECT job_id, chunk_id, mapper_version, xml_documento, status FROM quarantine WHERE id = $1",
+                &[&id],
+            ).await?.with_context(|| format!("No quarantined chunk with id {}", id))?;
+            let fixed = match args.get(2) {
+                Some(path) => Some(std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path))?),
+                None => None,
+            };
+            let status: String = row.get(4);
+            let xml_content = resubmitted_document(id, &status, row.get(3), fixed)?;
+            let chunk_id: i32 = row.get(1);
+            let msg = ResubmitMsg {
+                job_id: row.get(0),
+                chunk_id: chunk_id as u32,
+                xml_content,
+                mapper_version: row.get(2),
+                quarantine_id: id,
+            };
+
+            let queue = &config.queues.xml_validation;
+            let mut con = config.redis()?.connect().await?;
+            con.push(queue, &serde_json::to_string(&msg)?).await?;
+            db_client.execute("UPDATE quarantine SET state = 'resubmitted', updated_at = now() WHERE id = $1", &[&id]).await?;
+            println!("Resubmitted quarantined chunk {} (Job {} Chunk {}) to '{}'.", id, msg.job_id, msg.chunk_id, queue);
+        }
+        Some("discard") => {
+            let id = id_arg(1)?;
+            let n = db_client.execute("UPDATE quarantine SET state = 'discarded', updated_at = now() WHERE id = $1", &[&id]).await?;
+            if n == 0 {
+                bail!("No quarantined chunk with id {}", id);
+            }
+            println!("Discarded quarantined chunk {}.", id);
+        }
+        _ => bail!(USAGE),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resubmits_the_stored_document_unless_a_fixed_one_is_given() {
+        let stored = "<MarketReport>stored</MarketReport>".to_string();
+        let fixed = "<MarketReport>fixed</MarketReport>".to_string();
+        assert_eq!(resubmitted_document(1, "ERRO_VALIDACAO", stored.clone(), None).unwrap(), stored);
+        assert_eq!(resubmitted_document(1, "ERRO_PERSISTENCIA", stored.clone(), None).unwrap(), stored);
+        assert_eq!(resubmitted_document(1, "ERRO_VALIDACAO", stored, Some(fixed.clone())).unwrap(), fixed);
+    }
+
+    #[test]
+    fn conversion_failures_need_a_fixed_document() {
+        let err = resubmitted_document(7, "ERRO_CONVERSAO", String::new(), None).unwrap_err();
+        assert!(err.to_string().contains("Chunk 7 failed conversion"));
+        let fixed = "<MarketReport/>".to_string();
+        assert_eq!(resubmitted_document(7, "ERRO_CONVERSAO", String::new(), Some(fixed.clone())).unwrap(), fixed);
+    }
+
+    #[test]
+    fn resubmissions_carry_what_the_validator_reads() {
+        let msg = ResubmitMsg {
+            job_id: "job-1".to_string(),
+            chunk_id: 3,
+            xml_content: "<MarketReport/>".to_string(),
+            mapper_version: "1.0.0".to_string(),
+            quarantine_id: 42,
+        };
+        assert_eq!(
+            serde_json::to_value(&msg).unwrap(),
+            serde_json::json!({
+                "job_id": "job-1",
+                "chunk_id": 3,
+                "xml_content": "<MarketReport/>",
+                "mapper_version": "1.0.0",
+                "quarantine_id": 42,
+            })
+        );
+    }
+}
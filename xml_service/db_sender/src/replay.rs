@@ -0,0 +1,254 @@
+use anyhow::{bail, Context, Result};
+use chrono::{NaiveDate, Utc};
+use redis::AsyncCommands;
+use tokio_postgres::Client;
+
+use crate::completion::{job_key, ACTIVE_JOBS_KEY};
+use crate::config::Config;
+use crate::PipelineMsg;
+use xml_common::{chunk_state, redis_conn};
+
+// Reprocessing after a mapping fix. Every chunk reaching db_sender leaves the
+// converter input it was built from in chunk_sources; `db_sender replay`
+// pushes those inputs back into queue:csv_processing tagged with the new
+// mapper version and the replay's run number (REPLAY_RUN_FIELD, carried
+// through as the chunk's source). How the rows stored under the old version
+// are treated is set by replay.policy (REPLAY_POLICY):
+//   supersede  (default) a replayed chunk's earlier rows, whatever their
+//              mapper version, are deleted in the same statement that stores
+//              the new one, so replaying without bumping the version doesn't
+//              leave two copies. Chunks of a first run are inserted as is.
+//   keep       every stored row stays in xml_storage side by side
+// A converter running another mapper version than the one asked for reports
+// the chunk as failed (ERRO_CONVERSAO) rather than converting it.
+// Chunks that never reached db_sender have no source and aren't replayed, and
+// neither are jobs stored before chunk_sources existed: without the converter
+// input there is nothing to rebuild them from.
+const REPLAY_RUN_FIELD: &str = "replay_run";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+    Supersede,
+    Keep,
+}
+
+impl Policy {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "supersede" => Ok(Policy::Supersede),
+            "keep" => Ok(Policy::Keep),
+            other => bail!("Unknown replay policy '{}', expected supersede or keep", other),
+        }
+    }
+
+    // Whether storing this chunk replaces what earlier runs stored for it
+    pub fn supersedes(self, msg: &PipelineMsg) -> bool {
+        self == Policy::Supersede && is_replay(msg)
+    }
+
+    // $3 is bound as text and cast to xml by Postgres
+    pub fn insert_statement(self, msg: &PipelineMsg) -> &'static str {
+        match self.supersedes(msg) {
+            true => "WITH superseded AS (DELETE FROM xml_storage WHERE job_id = $1::text AND chunk_id = $2::int4) INSERT INTO xml_storage (job_id, chunk_id, xml_documento, mapper_version) VALUES ($1::text, $2::int4, ($3::text)::xml, $4::text)",
+            false => "INSERT INTO xml_storage (job_id, chunk_id, xml_documento, mapper_version) VALUES ($1::text, $2::int4, ($3::text)::xml, $4::text)",
+        }
+    }
+}
+
+pub async fn ensure_table(db_client: &Client) -> Result<()> {
+    db_client.batch_execute(
+        "CREATE TABLE IF NOT EXISTS chunk_sources (
+            job_id text NOT NULL,
+            chunk_id int4 NOT NULL,
+            input jsonb NOT NULL,
+            created_at timestamptz NOT NULL DEFAULT now(),
+            PRIMARY KEY (job_id, chunk_id)
+        )"
+    ).await.context("Failed to create chunk_sources table")?;
+    Ok(())
+}
+
+fn is_replay(msg: &PipelineMsg) -> bool {
+    msg.source.as_ref().is_some_and(|source| source.get(REPLAY_RUN_FIELD).is_some())
+}
+
+// The converter input as the first run received it: a replayed chunk carries
+// the same one plus the replay's mapper_version and run number
+fn original_input(source: &serde_json::Value) -> serde_json::Value {
+    let mut input = source.clone();
+    if let Some(fields) = input.as_object_mut() {
+        fields.remove("mapper_version");
+        fields.remove(REPLAY_RUN_FIELD);
+    }
+    input
+}
+
+// First writer wins, so created_at keeps pointing at the original run
+pub async fn store_source(db_client: &Client, msg: &PipelineMsg) -> Result<()> {
+    let Some(source) = &msg.source else {
+        return Ok(());
+    };
+    let input = original_input(source);
+    let chunk_id_i32: i32 = msg.chunk_id as i32;
+    db_client.execute(
+        "INSERT INTO chunk_sources (job_id, chunk_id, input) VALUES ($1::text, $2::int4, ($3::text)::jsonb) ON CONFLICT (job_id, chunk_id) DO NOTHING",
+        &[&msg.job_id, &chunk_id_i32, &serde_json::to_string(&input)?],
+    ).await?;
+    Ok(())
+}
+
+// Drops the chunk's earlier extra outputs once the new XML is in, before the
+// new ones are stored
+pub async fn supersede_outputs(db_client: &Client, msg: &PipelineMsg) -> Result<u64> {
+    let chunk_id_i32: i32 = msg.chunk_id as i32;
+    let n = db_client.execute(
+        "DELETE FROM report_outputs WHERE job_id = $1::text AND chunk_id = $2::int4",
+        &[&msg.job_id, &chunk_id_i32],
+    ).await?;
+    Ok(n)
+}
+
+const USAGE: &str = "usage: db_sender replay --mapper-version <version> (--job <job_id> | --from <YYYY-MM-DD> --to <YYYY-MM-DD>)";
+
+pub async fn run_cli(config: &Config, args: Vec<String>) -> Result<()> {
+    let mut mapper_version = None;
+    let mut job = None;
+    let mut from = None;
+    let mut to = None;
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        let value = rest.next().context(USAGE)?;
+        match arg.as_str() {
+            "--mapper-version" => mapper_version = Some(value.clone()),
+            "--job" => job = Some(value.clone()),
+            "--from" => from = Some(NaiveDate::parse_from_str(value, "%Y-%m-%d").context("--from must be YYYY-MM-DD")?),
+            "--to" => to = Some(NaiveDate::parse_from_str(value, "%Y-%m-%d").context("--to must be YYYY-MM-DD")?),
+            _ => bail!(USAGE),
+        }
+    }
+    let mapper_version = mapper_version.context(USAGE)?;
+
+    let db_url = config.database_url()?;
+    let db_client = crate::connect_postgres(db_url.expose(), &crate::postgres_tls()?)
+        .await
+        .context("Failed to connect to PostgreSQL")?;
+    ensure_table(&db_client).await?;
+
+    let jobs: Vec<String> = match (job, from, to) {
+        (Some(job), None, None) => vec![job],
+        (None, Some(from), Some(to)) => {
+            // --to is inclusive
+            let until = to.succ_opt().context("--to out of range")?;
+            db_client.query(
+                "SELECT DISTINCT job_id FROM chunk_sources WHERE created_at >= $1::text::date AND created_at < $2::text::date ORDER BY job_id",
+                &[&from.to_string(), &until.to_string()],
+            ).await?.iter().map(|row| row.get(0)).collect()
+        }
+        _ => bail!(USAGE),
+    };
+    if jobs.is_empty() {
+        println!("No recorded chunks match, nothing to replay.");
+        return Ok(());
+    }
+
+    let mut con = config.redis()?.connect().await?;
+
+    for job_id in jobs {
+        if let Err(e) = replay_job(&db_client, &mut con, &config.queues.csv_processing, &job_id, &mapper_version).await {
+            eprintln!("Skipping Job {}: {}", job_id, e);
+        }
+    }
+    Ok(())
+}
+
+async fn replay_job(db_client: &Client, con: &mut redis_conn::Connection, queue: &str, job_id: &str, mapper_version: &str) -> Result<()> {
+    let active: bool = con.sismember(ACTIVE_JOBS_KEY, job_id).await?;
+    if active {
+        bail!("job is still running");
+    }
+
+    let rows = db_client.query(
+        "SELECT chunk_id, input::text FROM chunk_sources WHERE job_id = $1::text ORDER BY chunk_id",
+        &[&job_id],
+    ).await?;
+    if rows.is_empty() {
+        bail!("no recorded chunk sources (jobs stored before chunk sources were recorded can't be replayed)");
+    }
+
+    // Start a fresh run of the job so completion counting and the supervisor
+    // work as for a new one. The run number keeps the replay's notification
+    // apart from the original one (see completion::finalise).
+    let now = Utc::now().to_rfc3339();
+    let run: i64 = con.incr(job_key(job_id, "replay"), 1).await?;
+    let mut stale: Vec<String> = ["finalized", "cancelled", "processed", "stats", "last_progress", "chunks", "tickers", "tracked_chunks"]
+        .iter()
+        .map(|name| job_key(job_id, name))
+        .collect();
+    stale.extend(rows.iter().map(|row| job_key(job_id, &format!("chunk:{}", row.get::<_, i32>(0)))));
+    let _: () = redis::pipe()
+        .del(&stale).ignore()
+        .set_ex(job_key(job_id, "total"), rows.len(), 86400).ignore()
+        .set_ex(job_key(job_id, "started_at"), &now, 86400).ignore()
+        .sadd(ACTIVE_JOBS_KEY, job_id).ignore()
+        .query_async(con)
+        .await?;
+
+    for row in &rows {
+        let chunk_id: i32 = row.get(0);
+        let input: String = row.get(1);
+        let mut input: serde_json::Value = serde_json::from_str(&input)?;
+        input["mapper_version"] = serde_json::Value::String(mapper_version.to_string());
+        input[REPLAY_RUN_FIELD] = serde_json::Value::from(run);
+        con.push(queue, &serde_json::to_string(&input)?).await?;
+        chunk_state::record(con, job_id, chunk_id as u32, "queued", None).await;
+    }
+    println!("Replaying Job {} (run {}): {} chunks queued with mapper {}.", job_id, run, rows.len(), mapper_version);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn chunk(source: Option<serde_json::Value>) -> PipelineMsg {
+        serde_json::from_value(json!({
+            "job_id": "job-1",
+            "chunk_id": 3,
+            "xml_content": "<MarketReport/>",
+            "status": "OK",
+            "mapper_version": "1.0.0",
+            "source": source,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_the_policies() {
+        assert_eq!(Policy::parse("supersede").unwrap(), Policy::Supersede);
+        assert_eq!(Policy::parse("keep").unwrap(), Policy::Keep);
+        assert!(Policy::parse("replace").is_err());
+    }
+
+    #[test]
+    fn only_replayed_chunks_supersede_earlier_rows() {
+        let first_run = chunk(Some(json!({ "job_id": "job-1", "chunk_id": 3 })));
+        let replayed = chunk(Some(json!({ "job_id": "job-1", "chunk_id": 3, "mapper_version": "1.1.0", "replay_run": 2 })));
+        let unrecorded = chunk(None);
+
+        assert!(Policy::Supersede.supersedes(&replayed));
+        assert!(!Policy::Supersede.supersedes(&first_run));
+        assert!(!Policy::Supersede.supersedes(&unrecorded));
+        assert!(!Policy::Keep.supersedes(&replayed));
+
+        assert!(Policy::Supersede.insert_statement(&replayed).contains("DELETE FROM xml_storage"));
+        assert!(!Policy::Supersede.insert_statement(&first_run).contains("DELETE"));
+        assert!(!Policy::Keep.insert_statement(&replayed).contains("DELETE"));
+    }
+
+    #[test]
+    fn recorded_sources_drop_the_replay_fields() {
+        let source = json!({ "job_id": "job-1", "chunk_id": 3, "s3_key": "k", "mapper_version": "1.1.0", "replay_run": 2 });
+        assert_eq!(original_input(&source), json!({ "job_id": "job-1", "chunk_id": 3, "s3_key": "k" }));
+    }
+}
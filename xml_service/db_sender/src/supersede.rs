@@ -0,0 +1,65 @@
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use tokio_postgres::Client;
+
+use xml_common::assets::{self, strip_assets, tickers_in};
+use xml_common::redis_conn;
+
+pub use xml_common::assets::Superseded;
+
+// Drops the superseded tickers from the stored chunk's XML; a chunk left with
+// no assets at all is deleted rather than kept as an empty report. Its extra
+// outputs (JSON / protobuf) are deleted rather than rewritten, so nothing
+// keeps serving the stale assets; `db_sender replay` rebuilds them.
+pub async fn apply(db_client: &Client, job_id: &str, superseded: &Superseded) -> Result<()> {
+    let chunk_id_i32: i32 = superseded.chunk_id as i32;
+    let rows = db_client
+        .query(
+            "SELECT xml_documento::text FROM xml_storage WHERE job_id = $1::text AND chunk_id = $2::int4",
+            &[&job_id, &chunk_id_i32],
+        )
+        .await
+        .context("Failed to load superseded chunk")?;
+
+    let drop: HashSet<String> = superseded.tickers.iter().cloned().collect();
+    for row in rows {
+        let xml: String = row.get(0);
+        let stripped = strip_assets(&xml, &drop)?;
+        if tickers_in(&stripped)?.is_empty() {
+            db_client
+                .execute(
+                    "DELETE FROM xml_storage WHERE job_id = $1::text AND chunk_id = $2::int4",
+                    &[&job_id, &chunk_id_i32],
+                )
+                .await
+                .context("Failed to delete superseded chunk")?;
+            tracing::info!(superseded_chunk_id = superseded.chunk_id, "duplicates.chunk_emptied");
+            continue;
+        }
+        db_client
+            .execute(
+                "UPDATE xml_storage SET xml_documento = ($3::text)::xml WHERE job_id = $1::text AND chunk_id = $2::int4",
+                &[&job_id, &chunk_id_i32, &stripped],
+            )
+            .await
+            .context("Failed to update superseded chunk")?;
+    }
+    let outputs = db_client
+        .execute(
+            "DELETE FROM report_outputs WHERE job_id = $1::text AND chunk_id = $2::int4",
+            &[&job_id, &chunk_id_i32],
+        )
+        .await
+        .context("Failed to delete superseded chunk's outputs")?;
+    if outputs > 0 {
+        tracing::info!(superseded_chunk_id = superseded.chunk_id, removed = outputs, "duplicates.outputs_dropped");
+    }
+    Ok(())
+}
+
+// A chunk that couldn't be stored gives back the tickers the validator claimed
+// for it, so a later chunk (or its own retry) can store them
+pub async fn release(con: &mut redis_conn::Connection, job_id: &str, chunk_id: u32, xml: &str, superseded: &[Superseded]) -> Result<()> {
+    let tickers = tickers_in(xml)?;
+    assets::release(con, job_id, chunk_id, &tickers, superseded).await
+}
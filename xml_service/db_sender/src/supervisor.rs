@@ -0,0 +1,163 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use redis::AsyncCommands;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_postgres::Client;
+
+use crate::cancel;
+use crate::completion::{self, job_key, ACTIVE_JOBS_KEY, JOB_TIMED_OUT};
+use xml_common::redis_conn;
+
+// Finalises jobs that will never complete on their own because a chunk was
+// lost somewhere upstream. A job in jobs:active is timed out when either
+//   - it has been running longer than JOB_DEADLINE_SECS, or
+//   - no chunk arrived for JOB_STALL_SECS.
+// The TIMED_OUT notification lists the chunk ids that never reached db_sender.
+// Cancelled jobs (see cancel.rs) are finalised as CANCELLED on the next sweep.
+pub struct Limits {
+    pub deadline: Duration,
+    pub stall: Duration,
+    pub interval: Duration,
+}
+
+pub async fn run(redis_client: redis_conn::Redis, db_client: Arc<Client>, settings: Arc<completion::Settings>, limits: Limits) {
+    tracing::info!(deadline_secs = limits.deadline.as_secs(), stall_secs = limits.stall.as_secs(), "supervisor.started");
+    loop {
+        tokio::time::sleep(limits.interval).await;
+        let mut con = match redis_client.connect().await {
+            Ok(con) => con,
+            Err(e) => {
+                tracing::error!(error = %e, "supervisor.redis_unavailable");
+                continue;
+            }
+        };
+        if let Err(e) = sweep(&mut con, &db_client, &settings, &limits).await {
+            tracing::error!(error = %e, "supervisor.sweep_failed");
+        }
+    }
+}
+
+// A job that fails (Redis hiccup, database error while deleting its rows) is
+// logged and retried on the next sweep; the other jobs are still supervised
+async fn sweep(con: &mut redis_conn::Connection, db_client: &Client, settings: &completion::Settings, limits: &Limits) -> Result<()> {
+    let jobs: Vec<String> = con.smembers(ACTIVE_JOBS_KEY).await?;
+    let now = Utc::now();
+
+    for job_id in jobs {
+        if let Err(e) = supervise(con, db_client, settings, limits, &job_id, now).await {
+            tracing::warn!(job_id = %job_id, error = %e, "supervisor.job_failed");
+        }
+    }
+    Ok(())
+}
+
+async fn supervise(
+    con: &mut redis_conn::Connection,
+    db_client: &Client,
+    settings: &completion::Settings,
+    limits: &Limits,
+    job_id: &str,
+    now: DateTime<Utc>,
+) -> Result<()> {
+    if let Some(cancellation) = cancel::requested(con, job_id).await? {
+        tracing::info!(job_id = %job_id, reason = %cancellation.reason, "job.cancelled");
+        return cancel::apply(con, db_client, settings, job_id, cancellation).await;
+    }
+
+    let started_at: Option<String> = con.get(job_key(job_id, "started_at")).await?;
+    let last_progress: Option<String> = con.get(job_key(job_id, "last_progress")).await?;
+    let Some(started_at) = parse(started_at.as_deref()) else {
+        // Keys expired (or never existed): nothing left to report on
+        let _: () = con.srem(ACTIVE_JOBS_KEY, job_id).await?;
+        return Ok(());
+    };
+    let Some(reason) = timeout_reason(limits, now, started_at, parse(last_progress.as_deref())) else {
+        return Ok(());
+    };
+
+    let total: Option<u32> = con.get(job_key(job_id, "total")).await?;
+    let seen: BTreeSet<u32> = con.smembers(job_key(job_id, "chunks")).await?;
+    let missing = missing_chunks(total, &seen);
+
+    tracing::warn!(job_id = %job_id, reason = %reason, seen = seen.len(), total, missing = ?missing, "job.timed_out");
+    completion::finalise(con, db_client, settings, job_id, Some(JOB_TIMED_OUT), Some(reason), missing).await
+}
+
+// Why the job should be timed out, if it should. A job with no progress yet
+// counts as idle since it started.
+fn timeout_reason(limits: &Limits, now: DateTime<Utc>, started_at: DateTime<Utc>, last_progress: Option<DateTime<Utc>>) -> Option<String> {
+    let running = (now - started_at).to_std().unwrap_or_default();
+    let idle = (now - last_progress.unwrap_or(started_at)).to_std().unwrap_or_default();
+    if running > limits.deadline {
+        Some(format!("deadline of {}s exceeded", limits.deadline.as_secs()))
+    } else if idle > limits.stall {
+        Some(format!("no progress for {}s", idle.as_secs()))
+    } else {
+        None
+    }
+}
+
+// Chunk ids 1..=total that never reached db_sender; unknown without a total
+fn missing_chunks(total: Option<u32>, seen: &BTreeSet<u32>) -> Vec<u32> {
+    match total {
+        Some(total) => (1..=total).filter(|id| !seen.contains(id)).collect(),
+        None => Vec::new(),
+    }
+}
+
+fn parse(ts: Option<&str>) -> Option<DateTime<Utc>> {
+    ts.and_then(|s| DateTime::parse_from_rfc3339(s).ok()).map(|t| t.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: Limits = Limits { deadline: Duration::from_secs(3600), stall: Duration::from_secs(600), interval: Duration::from_secs(30) };
+
+    fn at(ts: &str) -> DateTime<Utc> {
+        parse(Some(ts)).unwrap()
+    }
+
+    #[test]
+    fn times_out_past_the_deadline_even_with_progress() {
+        let reason = timeout_reason(&LIMITS, at("2026-01-01T11:00:01Z"), at("2026-01-01T10:00:00Z"), Some(at("2026-01-01T10:59:00Z")));
+        assert_eq!(reason.as_deref(), Some("deadline of 3600s exceeded"));
+    }
+
+    #[test]
+    fn times_out_a_stalled_job() {
+        let now = at("2026-01-01T10:30:00Z");
+        let reason = timeout_reason(&LIMITS, now, at("2026-01-01T10:00:00Z"), Some(at("2026-01-01T10:15:00Z")));
+        assert_eq!(reason.as_deref(), Some("no progress for 900s"));
+        // No chunk yet: idle since the job started
+        let reason = timeout_reason(&LIMITS, now, at("2026-01-01T10:00:00Z"), None);
+        assert_eq!(reason.as_deref(), Some("no progress for 1800s"));
+    }
+
+    #[test]
+    fn leaves_running_jobs_alone() {
+        let now = at("2026-01-01T10:30:00Z");
+        assert_eq!(timeout_reason(&LIMITS, now, at("2026-01-01T10:00:00Z"), Some(at("2026-01-01T10:25:00Z"))), None);
+        assert_eq!(timeout_reason(&LIMITS, now, at("2026-01-01T10:25:00Z"), None), None);
+        // Clock skew between hosts: timestamps in the future count as no time at all
+        assert_eq!(timeout_reason(&LIMITS, now, at("2026-01-01T10:31:00Z"), None), None);
+    }
+
+    #[test]
+    fn lists_chunks_that_never_arrived() {
+        let seen: BTreeSet<u32> = [1, 2, 4].into();
+        assert_eq!(missing_chunks(Some(5), &seen), [3, 5]);
+        assert!(missing_chunks(Some(2), &seen).is_empty());
+        assert!(missing_chunks(None, &seen).is_empty());
+    }
+
+    #[test]
+    fn ignores_unparseable_timestamps() {
+        assert_eq!(parse(Some("yesterday")), None);
+        assert_eq!(parse(None), None);
+        assert_eq!(parse(Some("2026-01-01T11:00:00+01:00")), Some(at("2026-01-01T10:00:00Z")));
+    }
+}
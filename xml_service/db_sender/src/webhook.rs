@@ -0,0 +1,206 @@
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio_postgres::Client;
+use tracing::Instrument;
+
+use xml_common::secret::Secret;
+use xml_common::telemetry;
+
+// Completion notifications go through a Postgres outbox so they survive a
+// restart: check_completion only enqueues, and `run_delivery` keeps retrying
+// with exponential backoff until the receiver answers 2xx or attempts run out.
+//
+// Every request carries:
+//   Idempotency-Key      stable per notification, receivers should dedupe on it
+//   X-Webhook-Timestamp  unix seconds when this attempt was signed
+//   X-Webhook-Signature  sha256=<hex HMAC-SHA256(secret, "<timestamp>.<body>")>
+//                        (only when WEBHOOK_SECRET is set)
+//   traceparent          W3C trace context of the delivery attempt, when tracing is on
+
+const BASE_BACKOFF_SECS: i64 = 2;
+const MAX_BACKOFF_SECS: i64 = 600;
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+// Rows claimed per poll; they are sent one after the other
+const BATCH_SIZE: i64 = 10;
+
+pub async fn ensure_table(db_client: &Client) -> Result<()> {
+    db_client.batch_execute(
+        "CREATE TABLE IF NOT EXISTS webhook_outbox (
+            id bigserial PRIMARY KEY,
+            idempotency_key text NOT NULL,
+            target text NOT NULL,
+            payload jsonb NOT NULL,
+            attempts int4 NOT NULL DEFAULT 0,
+            next_attempt_at timestamptz NOT NULL DEFAULT now(),
+            delivered_at timestamptz,
+            last_error text,
+            created_at timestamptz NOT NULL DEFAULT now(),
+            UNIQUE (idempotency_key, target)
+        )"
+    ).await.context("Failed to create webhook_outbox table")?;
+    // Tables created before notifications could fan out to several targets
+    // have UNIQUE (idempotency_key), which makes every target after the first
+    // a silent no-op in `enqueue`
+    db_client.batch_execute(
+        "ALTER TABLE webhook_outbox DROP CONSTRAINT IF EXISTS webhook_outbox_idempotency_key_key;
+        DO $$
+        BEGIN
+            IF NOT EXISTS (
+                SELECT 1 FROM pg_constraint
+                WHERE conrelid = 'webhook_outbox'::regclass AND conname = 'webhook_outbox_idempotency_key_target_key'
+            ) THEN
+                ALTER TABLE webhook_outbox ADD CONSTRAINT webhook_outbox_idempotency_key_target_key UNIQUE (idempotency_key, target);
+            END IF;
+        END $$"
+    ).await.context("Failed to migrate webhook_outbox to one row per target")?;
+    Ok(())
+}
+
+// Enqueuing the same key for the same target twice is a no-op, so a job can't
+// be announced twice to one receiver
+pub async fn enqueue(db_client: &Client, target: &str, idempotency_key: &str, payload: &impl serde::Serialize) -> Result<()> {
+    let body = serde_json::to_string(payload)?;
+    db_client.execute(
+        "INSERT INTO webhook_outbox (idempotency_key, target, payload) VALUES ($1::text, $2::text, ($3::text)::jsonb) ON CONFLICT (idempotency_key, target) DO NOTHING",
+        &[&idempotency_key, &target, &body],
+    ).await.context("Failed to enqueue webhook")?;
+    Ok(())
+}
+
+pub struct Delivery {
+    pub http: reqwest::Client,
+    // The timeout `http` was built with; bounds how long a claimed batch takes
+    pub timeout: Duration,
+    pub secret: Option<Secret>,
+    pub max_attempts: i32,
+}
+
+impl Delivery {
+    // How long claimed rows stay invisible to other replicas: long enough to
+    // send the whole batch, after which a replica that died mid-batch gives
+    // its rows back
+    fn lease_secs(&self) -> f64 {
+        (self.timeout.as_secs_f64() + 1.0) * BATCH_SIZE as f64 + 30.0
+    }
+}
+
+pub async fn run_delivery(db_client: Arc<Client>, delivery: Delivery) {
+    loop {
+        if let Err(e) = deliver_due(&db_client, &delivery).await {
+            tracing::error!(error = %e, "webhook.outbox_failed");
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+// With several db_sender replicas polling the same outbox, each due row is
+// claimed by exactly one of them: the claim pushes next_attempt_at past the
+// lease in the same statement that picks the rows, and SKIP LOCKED keeps a
+// concurrent poll from waiting on (or picking) the rows being claimed
+async fn deliver_due(db_client: &Client, delivery: &Delivery) -> Result<()> {
+    let rows = db_client.query(
+        "UPDATE webhook_outbox SET next_attempt_at = now() + make_interval(secs => $3)
+         WHERE id IN (
+             SELECT id FROM webhook_outbox
+             WHERE delivered_at IS NULL AND attempts < $1 AND next_attempt_at <= now()
+             ORDER BY id LIMIT $2
+             FOR UPDATE SKIP LOCKED
+         )
+         RETURNING id, idempotency_key, target, payload::text, attempts",
+        &[&delivery.max_attempts, &BATCH_SIZE, &delivery.lease_secs()],
+    ).await?;
+
+    for row in rows {
+        let (id, key, target, body, attempts): (i64, String, String, String, i32) =
+            (row.get(0), row.get(1), row.get(2), row.get(3), row.get(4));
+
+        let span = tracing::info_span!("webhook.send", idempotency_key = %key, %target, attempt = attempts + 1);
+        match send(delivery, &target, &key, body).instrument(span).await {
+            Ok(()) => {
+                db_client.execute("UPDATE webhook_outbox SET delivered_at = now(), attempts = attempts + 1, last_error = NULL WHERE id = $1", &[&id]).await?;
+                tracing::info!(idempotency_key = %key, %target, "webhook.delivered");
+            }
+            Err(e) => {
+                let attempt = attempts + 1;
+                let backoff = backoff_secs(attempt) as f64;
+                db_client.execute(
+                    "UPDATE webhook_outbox SET attempts = $2, last_error = $3::text, next_attempt_at = now() + make_interval(secs => $4) WHERE id = $1",
+                    &[&id, &attempt, &e.to_string(), &backoff],
+                ).await?;
+                if attempt >= delivery.max_attempts {
+                    tracing::error!(idempotency_key = %key, %target, attempt, error = %e, "webhook.gave_up");
+                } else {
+                    tracing::warn!(idempotency_key = %key, %target, attempt, max_attempts = delivery.max_attempts, retry_in_secs = backoff, error = %e, "webhook.failed");
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn send(delivery: &Delivery, target: &str, key: &str, body: String) -> Result<()> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs().to_string();
+    let mut request = delivery.http
+        .post(target)
+        .header("Content-Type", "application/json")
+        .header("Idempotency-Key", key)
+        .header("X-Webhook-Timestamp", &timestamp);
+    for (name, value) in telemetry::inject() {
+        request = request.header(name, value);
+    }
+    if let Some(secret) = &delivery.secret {
+        request = request.header("X-Webhook-Signature", sign(secret.expose(), &timestamp, &body));
+    }
+
+    let response = request.body(body).send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        anyhow::bail!("receiver answered {}", status);
+    }
+    Ok(())
+}
+
+// Delay before the next attempt after `attempt` failed ones: 4s, 8s, 16s ... capped at 10 minutes
+fn backoff_secs(attempt: i32) -> i64 {
+    (BASE_BACKOFF_SECS << attempt.clamp(0, 20)).min(MAX_BACKOFF_SECS)
+}
+
+fn sign(secret: &str, timestamp: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_the_timestamp_and_body() {
+        // echo -n '1700000000.{"job_id":"j"}' | openssl dgst -sha256 -hmac whsec_test
+        assert_eq!(
+            sign("whsec_test", "1700000000", r#"{"job_id":"j"}"#),
+            "sha256=3b982d06a3bf4bc7fbc8acbc3f60316fdc02c5aacdc068f09dca406de42ba0d2"
+        );
+        // A replayed body under a new timestamp needs a new signature
+        assert_ne!(sign("whsec_test", "1700000001", r#"{"job_id":"j"}"#), sign("whsec_test", "1700000000", r#"{"job_id":"j"}"#));
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_ten_minutes() {
+        let schedule: Vec<i64> = (1..=10).map(backoff_secs).collect();
+        assert_eq!(schedule, [4, 8, 16, 32, 64, 128, 256, 512, 600, 600]);
+        assert_eq!(backoff_secs(1000), MAX_BACKOFF_SECS);
+    }
+
+    #[test]
+    fn lease_covers_a_full_batch() {
+        let delivery = Delivery { http: reqwest::Client::new(), timeout: Duration::from_secs(10), secret: None, max_attempts: 8 };
+        assert!(delivery.lease_secs() >= 10.0 * BATCH_SIZE as f64);
+    }
+}
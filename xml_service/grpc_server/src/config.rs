@@ -0,0 +1,56 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+
+use xml_common::config::{DatabaseConfig, EnvKeys, LogConfig, MetricsConfig, RedisConfig, Settings};
+
+// The gRPC server's configuration; see xml_common::config for how it's
+// loaded. [redis] is the pipeline's Redis, only needed for job control: left
+// unset, the server answers queries only.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub redis: RedisConfig,
+    pub metrics: MetricsConfig,
+    pub log: LogConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            server: ServerConfig::default(),
+            database: DatabaseConfig::default(),
+            redis: RedisConfig::default(),
+            metrics: MetricsConfig { port: 9104 },
+            log: LogConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig { bind: "[::]:50051".to_string() }
+    }
+}
+
+impl Settings for Config {
+    const ENV_KEYS: &'static [(&'static str, &'static str)] = &[("server.bind", "GRPC_BIND_ADDR")];
+
+    fn validate(&self, keys: &EnvKeys) -> Result<()> {
+        self.server.bind.parse::<SocketAddr>().with_context(|| format!("Invalid server.bind '{}', expected host:port", self.server.bind))?;
+        keys.required("database.url", self.database.url.expose())?;
+        if self.redis.is_configured() {
+            self.redis.validate(keys)?;
+        }
+        self.metrics.validate(keys)?;
+        self.log.validate()
+    }
+}
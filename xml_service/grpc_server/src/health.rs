@@ -0,0 +1,73 @@
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+
+use xml_common::secret::Secret;
+
+// The check registry, /healthz and /readyz are shared with the queue workers
+// (see xml_common::health); grpc.health.v1 reports the same readiness.
+pub use xml_common::health::*;
+
+pub mod proto {
+    tonic::include_proto!("grpc.health.v1");
+}
+
+use proto::health_check_response::ServingStatus;
+use proto::health_server::Health;
+use proto::{HealthCheckRequest, HealthCheckResponse};
+
+pub async fn postgres_check(db_url: Secret) -> anyhow::Result<()> {
+    let tls_connector = native_tls::TlsConnector::builder().danger_accept_invalid_certs(true).build()?;
+    let (client, connection) = tokio_postgres::connect(db_url.expose(), postgres_native_tls::MakeTlsConnector::new(tls_connector)).await?;
+    tokio::spawn(connection);
+    client.simple_query("SELECT 1").await?;
+    Ok(())
+}
+
+// grpc.health.v1.Health over the services this server registered.
+// The empty service name stands for the server as a whole.
+pub struct HealthService {
+    pub services: Vec<&'static str>,
+}
+
+impl HealthService {
+    fn status(&self, service: &str) -> Option<ServingStatus> {
+        if !service.is_empty() && !self.services.contains(&service) {
+            return None;
+        }
+        Some(if is_ready() { ServingStatus::Serving } else { ServingStatus::NotServing })
+    }
+}
+
+#[tonic::async_trait]
+impl Health for HealthService {
+    type WatchStream = ReceiverStream<Result<HealthCheckResponse, Status>>;
+
+    async fn check(&self, request: Request<HealthCheckRequest>) -> Result<Response<HealthCheckResponse>, Status> {
+        let service = request.into_inner().service;
+        match self.status(&service) {
+            Some(status) => Ok(Response::new(HealthCheckResponse { status: status as i32 })),
+            None => Err(Status::not_found(format!("unknown service {}", service))),
+        }
+    }
+
+    async fn watch(&self, request: Request<HealthCheckRequest>) -> Result<Response<Self::WatchStream>, Status> {
+        let service = request.into_inner().service;
+        let known = self.status(&service).is_some();
+        let mut ready = subscribe();
+        let (tx, rx) = mpsc::channel(4);
+        tokio::spawn(async move {
+            loop {
+                let status = match (known, *ready.borrow_and_update()) {
+                    (false, _) => ServingStatus::ServiceUnknown,
+                    (true, true) => ServingStatus::Serving,
+                    (true, false) => ServingStatus::NotServing,
+                };
+                if tx.send(Ok(HealthCheckResponse { status: status as i32 })).await.is_err() || ready.changed().await.is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
@@ -0,0 +1,51 @@
+use tonic::{Request, Response, Status};
+
+use crate::bi_request::job_control_service_server::JobControlService;
+use crate::bi_request::{CancelJobReply, CancelJobRequest};
+use crate::metrics;
+use xml_common::{cancel, redis_conn};
+
+// Writes the same job:{id}:cancelled marker as `db_sender cancel` (see
+// xml_common::cancel); the workers drop the job's remaining chunks and
+// db_sender's supervisor announces it as CANCELLED.
+pub struct JobControl {
+    pub redis_client: redis_conn::Redis,
+}
+
+#[tonic::async_trait]
+impl JobControlService for JobControl {
+    async fn cancel_job(&self, request: Request<CancelJobRequest>) -> Result<Response<CancelJobReply>, Status> {
+        let result = self.cancel(request).await;
+        let status = match &result {
+            Ok(_) => "Ok".to_string(),
+            Err(status) => format!("{:?}", status.code()),
+        };
+        metrics::REQUESTS.with_label_values(&["CancelJob", &status]).inc();
+        result
+    }
+}
+
+impl JobControl {
+    async fn cancel(&self, request: Request<CancelJobRequest>) -> Result<Response<CancelJobReply>, Status> {
+        let req = request.into_inner();
+        let job_id = req.job_id.trim().to_string();
+        if job_id.is_empty() {
+            return Err(Status::invalid_argument("JobId is required"));
+        }
+
+        tracing::info!(job_id = %job_id, delete_rows = req.delete_stored_rows, reason = %req.reason, "job.cancel_requested");
+        let mut con = self.redis_client
+            .connect()
+            .await
+            .map_err(|e| Status::unavailable(format!("Redis Error: {}", e)))?;
+        cancel::request(&mut con, &job_id, &req.reason, req.delete_stored_rows)
+            .await
+            .map_err(|e| match e {
+                cancel::RequestError::Finished { .. } => Status::failed_precondition(e.to_string()),
+                cancel::RequestError::Unknown { .. } => Status::not_found(e.to_string()),
+                cancel::RequestError::Redis(_) => Status::internal(e.to_string()),
+            })?;
+
+        Ok(Response::new(CancelJobReply { job_id, state: "CANCELLING".to_string() }))
+    }
+}
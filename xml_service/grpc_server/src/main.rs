@@ -1,19 +1,50 @@
+mod config;
+mod health;
+mod jobs;
+mod metrics;
+mod shutdown;
+
+use tonic::metadata::KeyAndValueRef;
 use tonic::{transport::Server, Request, Response, Status};
 use native_tls::TlsConnector;
 use postgres_native_tls::MakeTlsConnector;
 use tokio_stream::wrappers::ReceiverStream;
 use tokio::sync::mpsc;
+use std::collections::HashMap;
 use std::env;
+use std::net::SocketAddr;
+use tracing::Instrument;
 
 pub mod bi_request {
     tonic::include_proto!("bi_request");
 }
 
 use bi_request::xml_query_service_server::{XmlQueryService, XmlQueryServiceServer};
+use bi_request::job_control_service_server::JobControlServiceServer;
+use health::proto::health_server::HealthServer;
 use bi_request::{Query, QueryResult};
+use xml_common::secret::Secret;
+use xml_common::{logging, redis_conn, telemetry};
+
+// Prefixes usable in XPath queries to target one schema version,
+// e.g. `/mr1:MarketReport/Asset`. Only the root is qualified, so queries on
+// child elements (`//Asset`) match every version, but an unprefixed root path
+// (`/MarketReport`) matches only documents written before versioning; use
+// `/*[local-name()='MarketReport']` to match the root of every version.
+const XPATH_NAMESPACES: &[(&str, &str)] = &[
+    ("mr1", "urn:tp3:market-report:v1"),
+];
+
+fn namespace_array() -> String {
+    let pairs: Vec<String> = XPATH_NAMESPACES
+        .iter()
+        .map(|(prefix, uri)| format!("ARRAY['{}', '{}']", prefix, uri))
+        .collect();
+    format!("ARRAY[{}]", pairs.join(", "))
+}
 
 pub struct MyXmlService {
-    db_url: String,
+    db_url: Secret,
 }
 
 #[tonic::async_trait]
@@ -24,11 +55,22 @@ impl XmlQueryService for MyXmlService {
         &self,
         request: Request<Query>,
     ) -> Result<Response<Self::GetQueryResultStream>, Status> {
+        // Callers may send W3C trace context (traceparent) as gRPC metadata
+        let carrier: HashMap<String, String> = request
+            .metadata()
+            .iter()
+            .filter_map(|entry| match entry {
+                KeyAndValueRef::Ascii(key, value) => Some((key.to_string(), value.to_str().ok()?.to_string())),
+                KeyAndValueRef::Binary(..) => None,
+            })
+            .collect();
         let req = request.into_inner();
         let xpath_query = req.query_string;
         let db_url = self.db_url.clone();
 
-        println!("Request received. Executing XPath: {}", xpath_query);
+        let span = tracing::info_span!("query", method = "GetQueryResult", xpath = %xpath_query);
+        telemetry::set_parent(&span, &carrier);
+        span.in_scope(|| tracing::info!("query.received"));
 
         let (tx, rx) = mpsc::channel(10);
         tokio::spawn(async move {
@@ -38,6 +80,8 @@ impl XmlQueryService for MyXmlService {
                 .build() {
                 Ok(c) => c,
                 Err(e) => {
+                    metrics::REQUESTS.with_label_values(&["GetQueryResult", "Internal"]).inc();
+                    tracing::error!(error = %e, "tls.build_failed");
                     let _ = tx.send(Err(Status::internal(format!("TLS Error: {}", e)))).await;
                     return;
                 }
@@ -45,41 +89,56 @@ impl XmlQueryService for MyXmlService {
             let tls = MakeTlsConnector::new(tls_connector);
 
             // 1. Connect to DB
-            match tokio_postgres::connect(&db_url, tls).await {
+            match tokio_postgres::connect(db_url.expose(), tls).await {
                 Ok((client, connection)) => {
                     tokio::spawn(async move {
                         if let Err(e) = connection.await {
-                            eprintln!("DB Connection error: {}", e);
+                            tracing::error!(error = %e, "postgres.connection_failed");
                         }
                     });
 
                     // 2. Execute SQL with XPath
-                    let sql = "SELECT unnest(xpath($1, xml_documento))::text FROM xml_storage";
+                    let sql = format!(
+                        "SELECT unnest(xpath($1, xml_documento, {}))::text FROM xml_storage",
+                        namespace_array()
+                    );
 
-                    match client.query(sql, &[&xpath_query]).await {
+                    let query = metrics::QUERY_SECONDS.start_timer();
+                    let queried = client.query(&sql, &[&xpath_query]).instrument(tracing::info_span!("db.xpath")).await;
+                    query.observe_duration();
+
+                    match queried {
                         Ok(rows) => {
                             let count = rows.len();
+                            let mut status = "Ok";
                             for row in rows {
                                 let val: String = row.get(0);
                                 let res = QueryResult { result: val };
                                 
                                 // Send match to stream
                                 if tx.send(Ok(res)).await.is_err() {
+                                    status = "Cancelled";
                                     break; // Client disconnected
                                 }
                             }
-                            println!("Streaming {} results finished.", count);
+                            metrics::REQUESTS.with_label_values(&["GetQueryResult", status]).inc();
+                            health::processed();
+                            tracing::info!(results = count, status, "query.finished");
                         }
                         Err(e) => {
+                            metrics::REQUESTS.with_label_values(&["GetQueryResult", "Internal"]).inc();
+                            tracing::error!(error = %e, "query.failed");
                             let _ = tx.send(Err(Status::internal(format!("SQL Error: {}", e)))).await;
                         }
                     }
                 }
                 Err(e) => {
+                    metrics::REQUESTS.with_label_values(&["GetQueryResult", "Internal"]).inc();
+                    tracing::error!(error = %e, "postgres.connect_failed");
                     let _ = tx.send(Err(Status::internal(format!("DB Connect Failed: {}", e)))).await;
                 }
             }
-        });
+        }.instrument(span));
 
         Ok(Response::new(ReceiverStream::new(rx)))
     }
@@ -87,18 +146,47 @@ impl XmlQueryService for MyXmlService {
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let addr = "[::]:50051".parse()?;
-    let db_url = ensure_sslmode_require(&env::var("DATABASE_URL").expect("DATABASE_URL must be set"));
-
+    let (config, rest) = xml_common::config::load::<config::Config>(env::args().skip(1).collect())?;
+    if let Some(arg) = rest.first() {
+        return Err(anyhow::anyhow!("Unexpected argument '{}'", arg).into());
+    }
+    logging::init(env!("CARGO_PKG_NAME"), &config.log);
+    let addr: SocketAddr = config.server.bind.parse()?;
+    let db_url = Secret::new(ensure_sslmode_require(config.database.url.expose()));
+    let health_db_url = db_url.clone();
     let service = MyXmlService { db_url };
+    tokio::spawn(health::watch("postgres", move || health::postgres_check(health_db_url.clone())));
+
+    // Job control needs the pipeline's Redis; without one only queries are served
+    let job_control = if !config.redis.is_configured() {
+        tracing::warn!("jobs.control_disabled");
+        None
+    } else {
+        let redis_client = redis_conn::Redis::open(&config.redis)?;
+        tracing::info!(redis = %redis_client.target(), "jobs.control_enabled");
+        let health_client = redis_client.clone();
+        tokio::spawn(health::watch("redis", move || health::redis_check(health_client.clone())));
+        Some(JobControlServiceServer::new(jobs::JobControl { redis_client }))
+    };
+
+    let mut served = vec!["bi_request.XmlQueryService"];
+    if job_control.is_some() {
+        served.push("bi_request.JobControlService");
+    }
+    let health_service = HealthServer::new(health::HealthService { services: served });
 
-    println!("gRPC Server listening on {}", addr);
+    tokio::spawn(metrics::serve(config.metrics.port, health::responsive, health::readiness));
+    tracing::info!(%addr, "service.started");
 
     Server::builder()
+        .add_service(health_service)
         .add_service(XmlQueryServiceServer::new(service))
-        .serve(addr)
+        .add_optional_service(job_control)
+        .serve_with_shutdown(addr, shutdown::signal_received())
         .await?;
 
+    tracing::info!("service.stopped");
+    telemetry::shutdown();
     Ok(())
 }
 
@@ -0,0 +1,13 @@
+use prometheus::{register_histogram, register_int_counter_vec, Histogram, IntCounterVec};
+use std::sync::LazyLock;
+
+// Served by xml_common::metrics on METRICS_PORT (default 9104), next to the
+// /healthz and /readyz probes of health.rs
+pub use xml_common::metrics::serve;
+
+pub static REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("grpc_requests_total", "gRPC requests by method and final status code", &["method", "status"]).unwrap()
+});
+pub static QUERY_SECONDS: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!("grpc_query_seconds", "Time to run an XPath query against xml_storage").unwrap()
+});
@@ -0,0 +1,10 @@
+use crate::health;
+
+// Resolves on SIGTERM or SIGINT. Handed to tonic's serve_with_shutdown, which
+// then stops accepting connections and waits for the calls in progress,
+// streaming queries included, to complete.
+pub async fn signal_received() {
+    xml_common::shutdown::signal_received().await;
+    tracing::info!("shutdown.requested");
+    health::draining();
+}
@@ -0,0 +1,64 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::duplicates::DuplicatePolicy;
+use crate::rules::{RuleConfig, RuleSet};
+use xml_common::config::{EnvKeys, LogConfig, MetricsConfig, Queues, RedisConfig, Settings, WorkerConfig};
+
+// The validator's configuration; see xml_common::config for how it's loaded
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub redis: RedisConfig,
+    pub queues: Queues,
+    pub worker: WorkerConfig,
+    pub validation: ValidationConfig,
+    pub metrics: MetricsConfig,
+    pub log: LogConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            redis: RedisConfig::default(),
+            queues: Queues::default(),
+            worker: WorkerConfig::default(),
+            validation: ValidationConfig::default(),
+            metrics: MetricsConfig { port: 9102 },
+            log: LogConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ValidationConfig {
+    // flag, first-wins, last-wins or reject (see duplicates.rs)
+    pub duplicate_ticker_policy: String,
+    // Overrides of the built-in business rules by name (see rules.rs)
+    pub rules: BTreeMap<String, RuleConfig>,
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        ValidationConfig { duplicate_ticker_policy: "flag".to_string(), rules: BTreeMap::new() }
+    }
+}
+
+impl Settings for Config {
+    const ENV_KEYS: &'static [(&'static str, &'static str)] = &[
+        ("validation.duplicate_ticker_policy", "DUPLICATE_TICKER_POLICY"),
+        ("validation.rules", "VALIDATION_RULES"),
+    ];
+
+    fn validate(&self, keys: &EnvKeys) -> Result<()> {
+        self.redis.validate(keys)?;
+        self.queues.validate(keys)?;
+        self.worker.validate(keys)?;
+        self.metrics.validate(keys)?;
+        DuplicatePolicy::parse(&self.validation.duplicate_ticker_policy)?;
+        RuleSet::new(&self.validation.rules)?;
+        self.log.validate()
+    }
+}
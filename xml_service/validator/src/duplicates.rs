@@ -0,0 +1,196 @@
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, HashSet};
+
+use crate::rules::{Finding, Severity};
+use xml_common::assets::{self, strip_assets, tickers_in, ClaimMode};
+use xml_common::redis_conn;
+
+pub use xml_common::assets::Superseded;
+
+pub const CROSS_CHUNK_DUPLICATE: &str = "cross_chunk_duplicate";
+
+// What to do when a ticker already seen in another chunk of the same job shows up
+// again. "First" and "last" follow validation order, not chunk ids.
+//   flag       - keep both, report a warning
+//   first-wins - drop the asset from the incoming chunk
+//   last-wins  - keep the incoming asset and ask db_sender to drop the older one
+//   reject     - fail the incoming chunk
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DuplicatePolicy {
+    Flag,
+    FirstWins,
+    LastWins,
+    Reject,
+}
+
+impl DuplicatePolicy {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "flag" => Ok(DuplicatePolicy::Flag),
+            "first-wins" => Ok(DuplicatePolicy::FirstWins),
+            "last-wins" => Ok(DuplicatePolicy::LastWins),
+            "reject" => Ok(DuplicatePolicy::Reject),
+            other => bail!("Unknown duplicate ticker policy '{}', expected flag, first-wins, last-wins or reject", other),
+        }
+    }
+
+    fn claim_mode(self) -> ClaimMode {
+        match self {
+            DuplicatePolicy::Flag | DuplicatePolicy::FirstWins => ClaimMode::Free,
+            DuplicatePolicy::LastWins => ClaimMode::All,
+            DuplicatePolicy::Reject => ClaimMode::NoneIfTaken,
+        }
+    }
+}
+
+pub struct DuplicateOutcome {
+    pub findings: Vec<Finding>,
+    pub xml: Option<String>,
+    pub supersedes: Vec<Superseded>,
+}
+
+pub async fn check(
+    con: &mut redis_conn::Connection,
+    policy: DuplicatePolicy,
+    job_id: &str,
+    chunk_id: u32,
+    xml: &str,
+) -> Result<DuplicateOutcome> {
+    let tickers = tickers_in(xml)?;
+    if tickers.is_empty() {
+        return Ok(DuplicateOutcome { findings: Vec::new(), xml: None, supersedes: Vec::new() });
+    }
+    let owners = assets::claim(con, job_id, chunk_id, policy.claim_mode(), &tickers).await?;
+    resolve(policy, xml, &tickers, owners)
+}
+
+// Applies the policy once the owners of the chunk's tickers are known
+// (`owners[i]` is the chunk owning `tickers[i]`, "" when it was free)
+fn resolve(policy: DuplicatePolicy, xml: &str, tickers: &[String], owners: Vec<String>) -> Result<DuplicateOutcome> {
+    let mut outcome = DuplicateOutcome { findings: Vec::new(), xml: None, supersedes: Vec::new() };
+    let mut duplicates: BTreeMap<u32, Vec<String>> = BTreeMap::new();
+    for (ticker, owner) in tickers.iter().zip(owners) {
+        if let Ok(owner) = owner.parse::<u32>() {
+            duplicates.entry(owner).or_default().push(ticker.clone());
+        }
+    }
+
+    let severity = if policy == DuplicatePolicy::Reject { Severity::Error } else { Severity::Warn };
+    for (owner, dup_tickers) in &duplicates {
+        for ticker in dup_tickers {
+            outcome.findings.push(Finding {
+                rule: CROSS_CHUNK_DUPLICATE.to_string(),
+                severity,
+                ticker: Some(ticker.clone()),
+                message: format!("Ticker already stored by chunk {} ({:?})", owner, policy),
+            });
+        }
+    }
+
+    match policy {
+        DuplicatePolicy::FirstWins if !duplicates.is_empty() => {
+            let dropped: HashSet<String> = duplicates.into_values().flatten().collect();
+            // Nothing of the chunk is left to store
+            if tickers.iter().all(|t| dropped.contains(t)) {
+                outcome.findings.push(Finding {
+                    rule: CROSS_CHUNK_DUPLICATE.to_string(),
+                    severity: Severity::Error,
+                    ticker: None,
+                    message: format!("All {} assets were already stored by other chunks", tickers.len()),
+                });
+            }
+            outcome.xml = Some(strip_assets(xml, &dropped)?);
+        }
+        DuplicatePolicy::LastWins => {
+            outcome.supersedes = duplicates
+                .into_iter()
+                .map(|(chunk_id, tickers)| Superseded { chunk_id, tickers })
+                .collect();
+        }
+        _ => (),
+    }
+    Ok(outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::has_errors;
+
+    const CHUNK: &str = concat!(
+        r#"<mr:MarketReport xmlns:mr="urn:tp3:market-report:v1" JobID="j">"#,
+        r#"<Asset Ticker="AAPL"><Name>Apple</Name></Asset>"#,
+        r#"<Asset Ticker="MSFT"><Name>Microsoft</Name></Asset>"#,
+        r#"<Asset Ticker="TSLA"><Name>Tesla</Name></Asset>"#,
+        r#"</mr:MarketReport>"#,
+    );
+
+    // Outcome for CHUNK when MSFT is owned by chunk 3 and TSLA by chunk 1
+    fn outcome(policy: DuplicatePolicy, owners: &[&str]) -> DuplicateOutcome {
+        let tickers = tickers_in(CHUNK).unwrap();
+        resolve(policy, CHUNK, &tickers, owners.iter().map(|o| o.to_string()).collect()).unwrap()
+    }
+
+    const TAKEN: &[&str] = &["", "3", "1"];
+
+    #[test]
+    fn parses_policies_and_claims_accordingly() {
+        assert_eq!(DuplicatePolicy::parse("first-wins").unwrap().claim_mode(), ClaimMode::Free);
+        assert_eq!(DuplicatePolicy::parse("flag").unwrap().claim_mode(), ClaimMode::Free);
+        assert_eq!(DuplicatePolicy::parse("last-wins").unwrap().claim_mode(), ClaimMode::All);
+        assert_eq!(DuplicatePolicy::parse("reject").unwrap().claim_mode(), ClaimMode::NoneIfTaken);
+        assert!(DuplicatePolicy::parse("newest").is_err());
+    }
+
+    #[test]
+    fn free_tickers_raise_nothing() {
+        for policy in [DuplicatePolicy::Flag, DuplicatePolicy::FirstWins, DuplicatePolicy::LastWins, DuplicatePolicy::Reject] {
+            let outcome = outcome(policy, &["", "", ""]);
+            assert!(outcome.findings.is_empty() && outcome.xml.is_none() && outcome.supersedes.is_empty(), "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn flag_warns_and_keeps_the_chunk() {
+        let outcome = outcome(DuplicatePolicy::Flag, TAKEN);
+        let flagged: Vec<_> = outcome.findings.iter().map(|f| (f.ticker.as_deref(), f.severity)).collect();
+        assert_eq!(flagged, [(Some("TSLA"), Severity::Warn), (Some("MSFT"), Severity::Warn)]);
+        assert!(outcome.xml.is_none() && outcome.supersedes.is_empty());
+    }
+
+    #[test]
+    fn first_wins_strips_the_taken_assets() {
+        let outcome = outcome(DuplicatePolicy::FirstWins, TAKEN);
+        assert!(!has_errors(&outcome.findings));
+        assert_eq!(tickers_in(&outcome.xml.unwrap()).unwrap(), ["AAPL"]);
+        assert!(outcome.supersedes.is_empty());
+    }
+
+    #[test]
+    fn first_wins_rejects_a_chunk_with_nothing_left() {
+        let outcome = outcome(DuplicatePolicy::FirstWins, &["2", "3", "1"]);
+        assert!(has_errors(&outcome.findings));
+        assert!(outcome.findings.iter().any(|f| f.ticker.is_none() && f.message == "All 3 assets were already stored by other chunks"));
+        assert!(tickers_in(&outcome.xml.unwrap()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn last_wins_supersedes_the_older_chunks() {
+        let outcome = outcome(DuplicatePolicy::LastWins, &["", "3", "3"]);
+        assert!(!has_errors(&outcome.findings));
+        assert!(outcome.xml.is_none());
+        assert_eq!(outcome.supersedes, [Superseded { chunk_id: 3, tickers: vec!["MSFT".to_string(), "TSLA".to_string()] }]);
+
+        let outcome = self::outcome(DuplicatePolicy::LastWins, TAKEN);
+        let owners: Vec<u32> = outcome.supersedes.iter().map(|s| s.chunk_id).collect();
+        assert_eq!(owners, [1, 3]);
+    }
+
+    #[test]
+    fn reject_fails_the_chunk() {
+        let outcome = outcome(DuplicatePolicy::Reject, TAKEN);
+        assert!(has_errors(&outcome.findings));
+        assert_eq!(outcome.findings.len(), 2);
+        assert!(outcome.xml.is_none() && outcome.supersedes.is_empty());
+    }
+}
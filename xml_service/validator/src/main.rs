@@ -1,9 +1,17 @@
-use anyhow::{Context, Result};
+mod config;
+mod duplicates;
+mod metrics;
+mod rules;
+
+use anyhow::{bail, Context, Result};
 use quick_xml::events::Event;
 use quick_xml::Reader;
-use redis::AsyncCommands;
 use serde::{Deserialize, Serialize};
-use std::env;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::Instrument;
+use xml_common::{cancel, chunk_state, health, logging, redis_conn, shutdown, telemetry, worker};
 
 #[derive(Serialize, Deserialize, Debug)]
 struct XmlMsg {
@@ -11,6 +19,16 @@ struct XmlMsg {
     chunk_id: u32,
     xml_content: String,
     mapper_version: String,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    outputs: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    quarantine_id: Option<i64>,
+    // Original converter input, passed through untouched for db_sender's replay
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<serde_json::Value>,
+    // W3C trace context (traceparent) of the sender, see telemetry.rs
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    trace_context: HashMap<String, String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -20,81 +38,280 @@ struct PipelineMsg {
     xml_content: String,
     status: String, 
     mapper_version: String,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    outputs: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    findings: Vec<rules::Finding>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    supersedes: Vec<duplicates::Superseded>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    quarantine_id: Option<i64>,
+    // Original converter input, passed through untouched for db_sender's replay
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<serde_json::Value>,
+    // W3C trace context (traceparent) of the sender, see telemetry.rs
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    trace_context: HashMap<String, String>,
 }
 
-fn validate_schema(xml: &str) -> bool {
+// Namespace of each versioned layout. Documents written before versioning
+// have no SchemaVersion attribute and are validated with the legacy rules.
+const SCHEMA_V1_NAMESPACE: &str = "urn:tp3:market-report:v1";
+
+#[derive(Default)]
+struct DocFacts {
+    has_root: bool,
+    root_namespace: Option<String>,
+    schema_version: Option<String>,
+    has_job_id: bool,
+    asset_count: usize,
+    has_fundamentals: bool,
+    has_daily_data: bool,
+}
+
+fn attr_value(e: &quick_xml::events::BytesStart, name: &str) -> Option<String> {
+    e.try_get_attribute(name)
+        .ok()
+        .flatten()
+        .and_then(|a| a.unescape_value().ok().map(|v| v.into_owned()))
+}
+
+fn collect_facts(xml: &str) -> Option<DocFacts> {
     let mut reader = Reader::from_str(xml);
     reader.trim_text(true);
     let mut buf = Vec::new();
-    let mut has_root = false;
-    let mut has_job_id = false;
-    let mut asset_count = 0;
-    let mut has_fundamentals = false;
-    let mut has_daily_data = false;
+    let mut facts = DocFacts::default();
 
     loop {
         match reader.read_event_into(&mut buf) {
             Ok(Event::Start(e)) => {
-                match e.name().as_ref() {
+                match e.local_name().as_ref() {
                     b"MarketReport" => {
-                        has_root = true;
-                        if e.try_get_attribute("JobID").is_ok() && e.try_get_attribute("GeneratedAt").is_ok() {
-                            has_job_id = true;
+                        facts.has_root = true;
+                        if attr_value(&e, "JobID").is_some() && attr_value(&e, "GeneratedAt").is_some() {
+                            facts.has_job_id = true;
                         }
+                        facts.schema_version = attr_value(&e, "SchemaVersion");
+                        facts.root_namespace = match e.name().prefix() {
+                            Some(prefix) => {
+                                let xmlns = format!("xmlns:{}", String::from_utf8_lossy(prefix.as_ref()));
+                                attr_value(&e, &xmlns)
+                            }
+                            None => attr_value(&e, "xmlns"),
+                        };
                     },
-                    b"Asset" => asset_count += 1,
-                    b"FundamentalData" => has_fundamentals = true,
-                    b"DailyData" => has_daily_data = true,
+                    b"Asset" => facts.asset_count += 1,
+                    b"FundamentalData" => facts.has_fundamentals = true,
+                    b"DailyData" => facts.has_daily_data = true,
                     _ => (),
                 }
             }
             Ok(Event::Eof) => break, 
-            Err(_) => return false, 
+            Err(_) => return None, 
             _ => (),
         }
         buf.clear();
     }
-    has_root && has_job_id && asset_count > 0 && has_fundamentals && has_daily_data
+    Some(facts)
+}
+
+fn validate_legacy(facts: &DocFacts) -> bool {
+    facts.has_root && facts.has_job_id && facts.asset_count > 0 && facts.has_fundamentals && facts.has_daily_data
+}
+
+fn validate_v1(facts: &DocFacts) -> bool {
+    validate_legacy(facts) && facts.root_namespace.as_deref() == Some(SCHEMA_V1_NAMESPACE)
+}
+
+fn validate_schema(xml: &str) -> bool {
+    let Some(facts) = collect_facts(xml) else { return false };
+    match facts.schema_version.as_deref() {
+        None => validate_legacy(&facts),
+        Some("1.0") => validate_v1(&facts),
+        Some(other) => {
+            tracing::warn!(schema_version = other, "schema.unknown_version");
+            false
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let redis_host = env::var("REDIS_HOST").context("REDIS_HOST missing")?;
-    let redis_port = env::var("REDIS_PORT").unwrap_or("6379".to_string());
-    let redis_password = env::var("REDIS_PASSWORD").unwrap_or_default();
-    
-    let redis_url = if !redis_password.is_empty() {
-        format!("redis://:{}@{}:{}", redis_password, redis_host, redis_port)
-    } else {
-        format!("redis://{}:{}", redis_host, redis_port)
-    };
+    // The rules used to come from a JSON file; fail rather than silently run
+    // with the defaults
+    if std::env::var_os("RULES_CONFIG").is_some() {
+        bail!("RULES_CONFIG is no longer read, move the rules to [validation.rules] in the config file or VALIDATION_RULES");
+    }
+    let (config, rest) = xml_common::config::load::<config::Config>(std::env::args().skip(1).collect())?;
+    if let Some(arg) = rest.first() {
+        bail!("Unexpected argument '{}'", arg);
+    }
+    let config = Arc::new(config);
+    logging::init(env!("CARGO_PKG_NAME"), &config.log);
 
-    let client = redis::Client::open(redis_url)?;
-    let mut con = client.get_tokio_connection().await?;
+    let rule_set = Arc::new(rules::RuleSet::new(&config.validation.rules)?);
+    let duplicate_policy = duplicates::DuplicatePolicy::parse(&config.validation.duplicate_ticker_policy)?;
 
-    println!("Validator Service Started. Listening...");
+    let client = redis_conn::Redis::open(&config.redis).context("Invalid redis settings")?;
 
-    loop {
-        let result: Option<(String, String)> = con.blpop("queue:xml_validation", 0.0).await?;
-        if let Some((_, json_str)) = result {
-            let in_msg: XmlMsg = match serde_json::from_str(&json_str) {
-                Ok(m) => m,
-                Err(e) => { eprintln!("JSON Error: {}", e); continue; }
-            };
-
-            let is_valid = validate_schema(&in_msg.xml_content);
-            let status = if is_valid { "OK".to_string() } else { "ERRO_VALIDACAO".to_string() };
-
-            let out_msg = PipelineMsg {
-                job_id: in_msg.job_id,
-                chunk_id: in_msg.chunk_id,
-                xml_content: in_msg.xml_content,
-                status,
-                mapper_version: in_msg.mapper_version,
-            };
-
-            let json_out = serde_json::to_string(&out_msg)?;
-            let _: () = con.rpush("queue:db_persistence", json_out).await?;
+    let queues = &config.queues;
+    tokio::spawn(metrics::serve(config.metrics.port, health::liveness, health::readiness));
+    tokio::spawn(metrics::watch_queues(client.clone(), vec![queues.xml_validation.clone(), queues.db_persistence.clone()]));
+    let health_client = client.clone();
+    tokio::spawn(health::watch("redis", move || health::redis_check(health_client.clone())));
+
+    let shutdown = shutdown::Shutdown::listen(Duration::from_secs(config.worker.shutdown_timeout_secs));
+    let concurrency = config.worker.concurrency;
+    tracing::info!(redis = %client.target(), queue = %queues.xml_validation, concurrency, "service.started");
+
+    let validators = (0..concurrency)
+        .map(|_| Validator { rule_set: rule_set.clone(), duplicate_policy, config: config.clone() })
+        .collect();
+    worker::run(&client, &queues.xml_validation, config.worker.poll_timeout_secs, validators, &shutdown).await?;
+
+    tracing::info!("service.stopped");
+    telemetry::shutdown();
+    Ok(())
+}
+
+// One of WORKER_CONCURRENCY consumers, each with its own Redis connection.
+// Ordering: chunks are popped in queue order but with more than one worker they
+// can reach queue:db_persistence in any order. The duplicate ticker policies
+// already follow validation order rather than chunk ids; claims are atomic in
+// Redis, so concurrent workers and replicas see each other's.
+struct Validator {
+    rule_set: Arc<rules::RuleSet>,
+    duplicate_policy: duplicates::DuplicatePolicy,
+    config: Arc<config::Config>,
+}
+
+impl worker::Handler for Validator {
+    type Message = XmlMsg;
+
+    fn span(&self, worker: usize, in_msg: &XmlMsg) -> tracing::Span {
+        let span = tracing::info_span!(
+            "chunk",
+            stage = "validate",
+            worker,
+            job_id = %in_msg.job_id,
+            chunk_id = in_msg.chunk_id,
+            mapper_version = %in_msg.mapper_version,
+        );
+        telemetry::set_parent(&span, &in_msg.trace_context);
+        span
+    }
+
+    // Re-validating a requeued chunk is safe: its own ticker claims don't count
+    // as duplicates
+    async fn handle(&self, con: &mut redis_conn::Connection, in_msg: XmlMsg, _payload: &str) -> Result<()> {
+        handle_chunk(con, &self.rule_set, self.duplicate_policy, &self.config.queues, in_msg).await
+    }
+}
+
+async fn handle_chunk(
+    con: &mut redis_conn::Connection,
+    rule_set: &rules::RuleSet,
+    duplicate_policy: duplicates::DuplicatePolicy,
+    queues: &xml_common::config::Queues,
+    in_msg: XmlMsg,
+) -> Result<()> {
+    if cancel::is_cancelled(con, &in_msg.job_id).await {
+        tracing::info!(reason = "job cancelled", "chunk.dropped");
+        chunk_state::record(con, &in_msg.job_id, in_msg.chunk_id, "cancelled", None).await;
+        return Ok(());
+    }
+    tracing::info!(xml_len = in_msg.xml_content.len(), "chunk.received");
+    chunk_state::record(con, &in_msg.job_id, in_msg.chunk_id, "validating", None).await;
+    let validation = metrics::VALIDATION_SECONDS.start_timer();
+
+    let (is_valid, mut findings) = tracing::info_span!("xml.validate").in_scope(|| {
+        let is_valid = validate_schema(&in_msg.xml_content);
+        let findings = if is_valid { rule_set.evaluate(&in_msg.xml_content) } else { Vec::new() };
+        (is_valid, findings)
+    });
+    let mut xml_content = in_msg.xml_content;
+    let mut supersedes = Vec::new();
+
+    // Tickers are only claimed for the job once the chunk itself is acceptable
+    if is_valid && !rules::has_errors(&findings) {
+        let check = duplicates::check(con, duplicate_policy, &in_msg.job_id, in_msg.chunk_id, &xml_content)
+            .instrument(tracing::info_span!("duplicates.check"));
+        match check.await {
+            Ok(outcome) => {
+                findings.extend(outcome.findings);
+                if let Some(stripped) = outcome.xml {
+                    xml_content = stripped;
+                }
+                supersedes = outcome.supersedes;
+            }
+            Err(e) => tracing::warn!(error = %e, "duplicates.check_failed"),
         }
     }
-}
\ No newline at end of file
+
+    for f in &findings {
+        tracing::info!(rule = %f.rule, severity = ?f.severity, ticker = f.ticker.as_deref().unwrap_or("-"), message = %f.message, "chunk.finding");
+    }
+    let status = if is_valid && !rules::has_errors(&findings) { "OK".to_string() } else { "ERRO_VALIDACAO".to_string() };
+    validation.observe_duration();
+    metrics::CHUNKS.with_label_values(&[&status]).inc();
+    if status != "OK" {
+        tracing::warn!(status = %status, schema_valid = is_valid, findings = findings.len(), "chunk.rejected");
+    }
+
+    let out_msg = PipelineMsg {
+        job_id: in_msg.job_id,
+        chunk_id: in_msg.chunk_id,
+        xml_content,
+        status,
+        mapper_version: in_msg.mapper_version,
+        outputs: in_msg.outputs,
+        findings,
+        supersedes,
+        quarantine_id: in_msg.quarantine_id,
+        source: in_msg.source,
+        trace_context: telemetry::inject(),
+    };
+
+    let json_out = serde_json::to_string(&out_msg)?;
+    // A rejected chunk still goes to db_sender, which quarantines it and counts it
+    if out_msg.status == "OK" {
+        chunk_state::record(con, &out_msg.job_id, out_msg.chunk_id, "validated", None).await;
+    } else {
+        let error = format!("validation: {} finding(s)", out_msg.findings.len());
+        chunk_state::record(con, &out_msg.job_id, out_msg.chunk_id, "failed", Some(&error)).await;
+    }
+    con.push(&queues.db_persistence, &json_out).await?;
+    metrics::PRODUCED.with_label_values(&[&queues.db_persistence]).inc();
+    tracing::info!(queue = %queues.db_persistence, status = %out_msg.status, "chunk.forwarded");
+    health::processed();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V1_ROOT: &str = r#"<mr:MarketReport xmlns:mr="urn:tp3:market-report:v1" SchemaVersion="1.0""#;
+    const BODY: &str = r#"><Asset Ticker="A"><FundamentalData></FundamentalData><DailyData></DailyData></Asset></mr:MarketReport>"#;
+
+    #[test]
+    fn accepts_a_complete_v1_document() {
+        let xml = format!(r#"{} JobID="j" GeneratedAt="2026-01-01T00:00:00Z"{}"#, V1_ROOT, BODY);
+        assert!(validate_schema(&xml));
+    }
+
+    #[test]
+    fn requires_job_id_and_generated_at() {
+        let without_job = format!(r#"{} GeneratedAt="2026-01-01T00:00:00Z"{}"#, V1_ROOT, BODY);
+        let without_time = format!(r#"{} JobID="j"{}"#, V1_ROOT, BODY);
+        assert!(!validate_schema(&without_job));
+        assert!(!validate_schema(&without_time));
+        assert!(!validate_schema(r#"<MarketReport><Asset Ticker="A"><FundamentalData></FundamentalData><DailyData></DailyData></Asset></MarketReport>"#));
+    }
+
+    #[test]
+    fn checks_the_namespace_of_versioned_documents() {
+        let xml = format!(r#"<mr:MarketReport xmlns:mr="urn:other" SchemaVersion="1.0" JobID="j" GeneratedAt="t"{}"#, BODY);
+        assert!(!validate_schema(&xml));
+    }
+}
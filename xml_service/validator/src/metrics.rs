@@ -0,0 +1,14 @@
+use prometheus::{register_histogram, register_int_counter_vec, Histogram, IntCounterVec};
+use std::sync::LazyLock;
+
+// The pipeline_* families and the /metrics server are shared (see
+// xml_common::metrics); these are the service's own.
+pub use xml_common::metrics::*;
+
+pub static VALIDATION_SECONDS: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!("validator_validation_seconds", "Time to validate a chunk (schema, rules and duplicate tickers)").unwrap()
+});
+
+pub static CHUNKS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("validator_chunks_total", "Validated chunks by resulting status", &["status"]).unwrap()
+});
@@ -0,0 +1,393 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+// Business rules run after the structural check. Each rule can be switched
+// off, given a severity and (where it makes sense) a threshold under
+// [validation.rules] in the config file; fields left out keep the defaults
+// below, e.g.
+//   [validation.rules.prev_close_deviation]
+//   severity = "error"
+//   threshold = 0.3
+// or as JSON in VALIDATION_RULES.
+// Warnings are reported but let the chunk through; errors reject it.
+
+pub const NEGATIVE_PRICE: &str = "negative_price";
+pub const ZERO_VOLUME: &str = "zero_volume";
+pub const PREV_CLOSE_DEVIATION: &str = "prev_close_deviation";
+pub const DUPLICATE_TICKER: &str = "duplicate_ticker";
+pub const SMA_MISMATCH: &str = "sma_mismatch";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warn,
+    Error,
+}
+
+// One entry of validation.rules. Every field is optional and overrides only
+// that field of the built-in rule, so `{"zero_volume": {"enabled": false}}`
+// keeps the rule's severity and a severity-only override keeps its threshold.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub severity: Option<Severity>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub threshold: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Rule {
+    enabled: bool,
+    severity: Severity,
+    threshold: Option<f64>,
+}
+
+pub struct RuleSet {
+    rules: HashMap<String, Rule>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Finding {
+    pub rule: String,
+    pub severity: Severity,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ticker: Option<String>,
+    pub message: String,
+}
+
+impl RuleSet {
+    pub fn defaults() -> Self {
+        let rule = |severity, threshold| Rule { enabled: true, severity, threshold };
+        let mut rules = HashMap::new();
+        rules.insert(NEGATIVE_PRICE.to_string(), rule(Severity::Error, None));
+        rules.insert(ZERO_VOLUME.to_string(), rule(Severity::Warn, None));
+        rules.insert(PREV_CLOSE_DEVIATION.to_string(), rule(Severity::Warn, Some(0.5)));
+        rules.insert(DUPLICATE_TICKER.to_string(), rule(Severity::Error, None));
+        rules.insert(SMA_MISMATCH.to_string(), rule(Severity::Warn, Some(0.05)));
+        RuleSet { rules }
+    }
+
+    // validation.rules overrides the defaults field by field
+    pub fn new(overrides: &BTreeMap<String, RuleConfig>) -> Result<Self> {
+        let mut set = Self::defaults();
+        for (name, config) in overrides {
+            let Some(rule) = set.rules.get_mut(name) else {
+                bail!("Unknown rule '{}' in validation.rules", name);
+            };
+            if let Some(threshold) = config.threshold {
+                if rule.threshold.is_none() {
+                    bail!("Rule '{}' in validation.rules takes no threshold", name);
+                }
+                if threshold.is_nan() || threshold < 0.0 {
+                    bail!("validation.rules.{}.threshold must be 0 or more, got {}", name, threshold);
+                }
+                rule.threshold = Some(threshold);
+            }
+            if let Some(enabled) = config.enabled {
+                rule.enabled = enabled;
+            }
+            if let Some(severity) = config.severity {
+                rule.severity = severity;
+            }
+        }
+        Ok(set)
+    }
+
+    fn active(&self, name: &str) -> Option<&Rule> {
+        self.rules.get(name).filter(|r| r.enabled)
+    }
+
+    pub fn evaluate(&self, xml: &str) -> Vec<Finding> {
+        let report: Report = match quick_xml::de::from_str(xml) {
+            Ok(r) => r,
+            Err(e) => {
+                return vec![Finding {
+                    rule: "schema".to_string(),
+                    severity: Severity::Error,
+                    ticker: None,
+                    message: format!("Report could not be read for business rules: {}", e),
+                }]
+            }
+        };
+
+        let mut findings = Vec::new();
+        let mut seen = HashSet::new();
+        for asset in &report.assets {
+            let mut report_finding = |name: &str, message: String| {
+                if let Some(rule) = self.active(name) {
+                    findings.push(Finding {
+                        rule: name.to_string(),
+                        severity: rule.severity,
+                        ticker: Some(asset.ticker.clone()),
+                        message,
+                    });
+                }
+            };
+
+            if !seen.insert(asset.ticker.as_str()) {
+                report_finding(DUPLICATE_TICKER, "Ticker appears more than once in this chunk".to_string());
+            }
+
+            let fundamentals = &asset.fundamental_data;
+            for (label, value) in [("OpenPrice", &fundamentals.open_price), ("PrevClose", &fundamentals.prev_close)] {
+                if let Some(v) = parse_number(value) {
+                    if v < 0.0 {
+                        report_finding(NEGATIVE_PRICE, format!("{} is negative ({})", label, v));
+                    }
+                }
+            }
+
+            let mut closes = Vec::new();
+            for day in &asset.daily_data.days {
+                if let Some(price) = parse_number(&day.closing_price.value) {
+                    if price < 0.0 {
+                        report_finding(NEGATIVE_PRICE, format!("Day {} closing price is negative ({})", day.index, price));
+                    }
+                    closes.push(price);
+                }
+                if parse_number(&day.volume) == Some(0.0) {
+                    report_finding(ZERO_VOLUME, format!("Day {} has volume 0", day.index));
+                }
+            }
+
+            let day_one = asset.daily_data.days.iter().find(|d| d.index == 1).and_then(|d| parse_number(&d.closing_price.value));
+            if let (Some(prev), Some(first)) = (parse_number(&fundamentals.prev_close), day_one) {
+                let threshold = self.threshold(PREV_CLOSE_DEVIATION);
+                if let Some(dev) = relative_deviation(prev, first) {
+                    if dev > threshold {
+                        report_finding(PREV_CLOSE_DEVIATION, format!("PrevClose {} differs {:.0}% from Day 1 close {}", prev, dev * 100.0, first));
+                    }
+                }
+            }
+
+            if let (Some(sma), false) = (parse_number(&asset.indicators.price_sma), closes.is_empty()) {
+                let mean = closes.iter().sum::<f64>() / closes.len() as f64;
+                let threshold = self.threshold(SMA_MISMATCH);
+                if let Some(dev) = relative_deviation(sma, mean) {
+                    if dev > threshold {
+                        report_finding(SMA_MISMATCH, format!("PriceSMA {} differs {:.1}% from mean of daily closes {:.2}", sma, dev * 100.0, mean));
+                    }
+                }
+            }
+        }
+        findings
+    }
+
+    // Only called for rules defined with a threshold, which an override can't remove
+    fn threshold(&self, name: &str) -> f64 {
+        self.rules[name].threshold.expect("rule defined with a threshold")
+    }
+}
+
+pub fn has_errors(findings: &[Finding]) -> bool {
+    findings.iter().any(|f| f.severity == Severity::Error)
+}
+
+fn parse_number(s: &str) -> Option<f64> {
+    s.trim().parse::<f64>().ok()
+}
+
+fn relative_deviation(value: f64, reference: f64) -> Option<f64> {
+    if reference == 0.0 {
+        return None;
+    }
+    Some(((value - reference) / reference).abs())
+}
+
+#[derive(Deserialize)]
+struct Report {
+    #[serde(rename = "Asset", default)]
+    assets: Vec<Asset>,
+}
+
+#[derive(Deserialize)]
+struct Asset {
+    #[serde(rename = "@Ticker")]
+    ticker: String,
+    #[serde(rename = "FundamentalData")]
+    fundamental_data: FundamentalData,
+    #[serde(rename = "Indicators")]
+    indicators: Indicators,
+    #[serde(rename = "DailyData")]
+    daily_data: DailyData,
+}
+
+#[derive(Deserialize)]
+struct FundamentalData {
+    #[serde(rename = "OpenPrice", default)]
+    open_price: String,
+    #[serde(rename = "PrevClose", default)]
+    prev_close: String,
+}
+
+#[derive(Deserialize)]
+struct Indicators {
+    #[serde(rename = "PriceSMA", default)]
+    price_sma: String,
+}
+
+#[derive(Deserialize)]
+struct DailyData {
+    #[serde(rename = "Day", default)]
+    days: Vec<Day>,
+}
+
+#[derive(Deserialize)]
+struct Day {
+    #[serde(rename = "@index")]
+    index: u8,
+    #[serde(rename = "ClosingPrice")]
+    closing_price: Price,
+    #[serde(rename = "Volume", default)]
+    volume: String,
+}
+
+#[derive(Deserialize)]
+struct Price {
+    #[serde(rename = "$text", default)]
+    value: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overrides(json: &str) -> BTreeMap<String, RuleConfig> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn disabling_keeps_the_rest_of_the_rule() {
+        let set = RuleSet::new(&overrides(r#"{"zero_volume": {"enabled": false}}"#)).unwrap();
+        let rule = &set.rules[ZERO_VOLUME];
+        assert!(!rule.enabled);
+        assert_eq!(rule.severity, Severity::Warn);
+        assert!(set.active(ZERO_VOLUME).is_none());
+    }
+
+    #[test]
+    fn severity_override_keeps_the_default_threshold() {
+        let set = RuleSet::new(&overrides(r#"{"prev_close_deviation": {"severity": "error"}}"#)).unwrap();
+        let rule = &set.rules[PREV_CLOSE_DEVIATION];
+        assert_eq!(rule.severity, Severity::Error);
+        assert_eq!(rule.threshold, Some(0.5));
+        assert!(rule.enabled);
+    }
+
+    #[test]
+    fn threshold_override_keeps_the_default_severity() {
+        let set = RuleSet::new(&overrides(r#"{"sma_mismatch": {"threshold": 0.1}}"#)).unwrap();
+        assert_eq!(set.rules[SMA_MISMATCH], Rule { enabled: true, severity: Severity::Warn, threshold: Some(0.1) });
+        assert_eq!(set.rules[NEGATIVE_PRICE], RuleSet::defaults().rules[NEGATIVE_PRICE]);
+    }
+
+    #[test]
+    fn rejects_bad_overrides() {
+        let err = |json| RuleSet::new(&overrides(json)).err().unwrap().to_string();
+        assert!(err(r#"{"no_such_rule": {}}"#).contains("Unknown rule 'no_such_rule'"));
+        assert!(err(r#"{"negative_price": {"threshold": 1}}"#).contains("takes no threshold"));
+        assert!(err(r#"{"sma_mismatch": {"threshold": -1}}"#).contains("must be 0 or more"));
+        assert!(serde_json::from_str::<BTreeMap<String, RuleConfig>>(r#"{"zero_volume": {"enable": false}}"#).is_err());
+    }
+
+    // <Asset> as the converter writes it; empty strings stand for missing values
+    fn asset(ticker: &str, open: &str, prev_close: &str, sma: &str, days: &[(u8, &str, &str)]) -> String {
+        let days: String = days
+            .iter()
+            .map(|(i, close, volume)| format!(r#"<Day index="{}"><ClosingPrice Currency="EUR">{}</ClosingPrice><Volume>{}</Volume></Day>"#, i, close, volume))
+            .collect();
+        format!(
+            r#"<Asset Ticker="{}"><FundamentalData><OpenPrice>{}</OpenPrice><PrevClose>{}</PrevClose></FundamentalData><Indicators><PriceSMA>{}</PriceSMA></Indicators><DailyData>{}</DailyData></Asset>"#,
+            ticker, open, prev_close, sma, days
+        )
+    }
+
+    fn report(assets: &[String]) -> String {
+        format!(r#"<mr:MarketReport xmlns:mr="urn:tp3:market-report:v1" SchemaVersion="1.0" JobID="j">{}</mr:MarketReport>"#, assets.concat())
+    }
+
+    fn fired(set: &RuleSet, assets: &[String]) -> Vec<(String, Severity, Option<String>)> {
+        set.evaluate(&report(assets)).into_iter().map(|f| (f.rule, f.severity, f.ticker)).collect()
+    }
+
+    fn finding(rule: &str, severity: Severity, ticker: &str) -> (String, Severity, Option<String>) {
+        (rule.to_string(), severity, Some(ticker.to_string()))
+    }
+
+    #[test]
+    fn clean_assets_raise_nothing() {
+        let set = RuleSet::defaults();
+        assert!(fired(&set, &[asset("A", "100", "100", "101", &[(1, "100", "900"), (2, "102", "800")])]).is_empty());
+    }
+
+    #[test]
+    fn negative_prices_are_errors() {
+        let set = RuleSet::defaults();
+        let findings = fired(&set, &[asset("A", "-1", "", "", &[(1, "100", "900"), (2, "-2", "900")])]);
+        assert_eq!(findings, [finding(NEGATIVE_PRICE, Severity::Error, "A"), finding(NEGATIVE_PRICE, Severity::Error, "A")]);
+        assert!(has_errors(&set.evaluate(&report(&[asset("A", "", "-5", "", &[])]))));
+    }
+
+    #[test]
+    fn zero_volume_warns_but_a_missing_volume_does_not() {
+        let set = RuleSet::defaults();
+        let findings = fired(&set, &[asset("A", "", "", "", &[(1, "100", "0"), (2, "100", ""), (3, "100", "900")])]);
+        assert_eq!(findings, [finding(ZERO_VOLUME, Severity::Warn, "A")]);
+    }
+
+    #[test]
+    fn prev_close_deviation_uses_the_threshold() {
+        let set = RuleSet::defaults();
+        // Measured against the Day 1 close: 150 / 250 = 60%, 40 / 140 = 29%
+        assert_eq!(fired(&set, &[asset("A", "", "100", "", &[(1, "250", "900")])]), [finding(PREV_CLOSE_DEVIATION, Severity::Warn, "A")]);
+        assert!(fired(&set, &[asset("A", "", "100", "", &[(1, "140", "900")])]).is_empty());
+        // Only Day 1 is compared, and a zero reference can't be compared at all
+        assert!(fired(&set, &[asset("A", "", "100", "", &[(2, "300", "900")])]).is_empty());
+        assert!(fired(&set, &[asset("A", "", "100", "", &[(1, "0", "900")])]).is_empty());
+
+        let strict = RuleSet::new(&overrides(r#"{"prev_close_deviation": {"threshold": 0.2}}"#)).unwrap();
+        assert_eq!(fired(&strict, &[asset("A", "", "100", "", &[(1, "140", "900")])]).len(), 1);
+    }
+
+    #[test]
+    fn sma_mismatch_compares_with_the_mean_close() {
+        let set = RuleSet::defaults();
+        let days = [(1, "100", "900"), (2, "110", "900")];
+        assert!(fired(&set, &[asset("A", "", "", "105", &days)]).is_empty());
+        assert!(fired(&set, &[asset("A", "", "", "109", &days)]).is_empty());
+        assert_eq!(fired(&set, &[asset("A", "", "", "120", &days)]), [finding(SMA_MISMATCH, Severity::Warn, "A")]);
+        // Nothing to compare with when no day has a close
+        assert!(fired(&set, &[asset("A", "", "", "120", &[])]).is_empty());
+    }
+
+    #[test]
+    fn duplicate_tickers_in_a_chunk_are_errors() {
+        let set = RuleSet::defaults();
+        let findings = fired(&set, &[asset("A", "", "", "", &[]), asset("B", "", "", "", &[]), asset("A", "", "", "", &[])]);
+        assert_eq!(findings, [finding(DUPLICATE_TICKER, Severity::Error, "A")]);
+    }
+
+    #[test]
+    fn findings_take_the_configured_severity() {
+        let zero = [asset("A", "", "", "", &[(1, "100", "0")])];
+        assert!(!has_errors(&RuleSet::defaults().evaluate(&report(&zero))));
+
+        let escalated = RuleSet::new(&overrides(r#"{"zero_volume": {"severity": "error"}}"#)).unwrap();
+        assert_eq!(fired(&escalated, &zero), [finding(ZERO_VOLUME, Severity::Error, "A")]);
+        assert!(has_errors(&escalated.evaluate(&report(&zero))));
+
+        let relaxed = RuleSet::new(&overrides(r#"{"negative_price": {"severity": "warn"}, "zero_volume": {"enabled": false}}"#)).unwrap();
+        assert_eq!(fired(&relaxed, &[asset("A", "-1", "", "", &[(1, "100", "0")])]), [finding(NEGATIVE_PRICE, Severity::Warn, "A")]);
+    }
+
+    #[test]
+    fn unreadable_reports_are_schema_errors() {
+        let findings = RuleSet::defaults().evaluate(r#"<MarketReport><Asset><DailyData><Day index="x"/></DailyData></Asset></MarketReport>"#);
+        assert_eq!(findings.len(), 1);
+        assert_eq!((findings[0].rule.as_str(), findings[0].severity), ("schema", Severity::Error));
+    }
+}
@@ -0,0 +1,246 @@
+use anyhow::Result;
+use quick_xml::events::Event;
+use quick_xml::{Reader, Writer};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+use crate::redis_conn;
+
+// Which chunk of a job owns each ticker, claimed by the validator:
+//   job:{id}:tickers  hash: ticker -> chunk id
+// Same lifetime the chunker gives the other job:{id}:* keys
+const TICKERS_TTL_SECS: i64 = 86400;
+
+// Tickers db_sender must remove from an already stored chunk. Sent by the
+// validator under the last-wins duplicate policy: these tickers now belong to
+// a newer chunk of the same job.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Superseded {
+    pub chunk_id: u32,
+    pub tickers: Vec<String>,
+}
+
+fn tickers_key(job_id: &str) -> String {
+    format!("job:{}:tickers", job_id)
+}
+
+// How `claim` treats the incoming chunk's tickers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClaimMode {
+    // Claim the tickers nobody owns
+    Free,
+    // Claim every ticker, taking over those of other chunks
+    All,
+    // Claim the free tickers only if none is owned by another chunk
+    NoneIfTaken,
+}
+
+impl ClaimMode {
+    fn as_str(self) -> &'static str {
+        match self {
+            ClaimMode::Free => "free",
+            ClaimMode::All => "all",
+            ClaimMode::NoneIfTaken => "none-if-taken",
+        }
+    }
+}
+
+// Returns the chunk already owning each ticker, "" when none (or this chunk).
+// Looking the owners up and claiming happen in one script, so two chunks of
+// the job validated at the same moment can't both take a ticker.
+pub async fn claim(con: &mut redis_conn::Connection, job_id: &str, chunk_id: u32, mode: ClaimMode, tickers: &[String]) -> Result<Vec<String>> {
+    let owners = redis::Script::new(CLAIM_SCRIPT)
+        .key(tickers_key(job_id))
+        .arg(chunk_id)
+        .arg(mode.as_str())
+        .arg(TICKERS_TTL_SECS)
+        .arg(tickers)
+        .invoke_async(con)
+        .await?;
+    Ok(owners)
+}
+
+// Gives up the tickers a chunk claimed but never got stored (db_sender failed
+// to insert it). Tickers it took over from older chunks (`superseded`, under
+// last-wins) go back to them, since those chunks still hold the assets; the
+// rest are freed. Tickers another chunk has claimed since are left alone.
+pub async fn release(con: &mut redis_conn::Connection, job_id: &str, chunk_id: u32, tickers: &[String], superseded: &[Superseded]) -> Result<()> {
+    let script = redis::Script::new(RELEASE_SCRIPT);
+    let mut invocation = script.key(tickers_key(job_id));
+    invocation.arg(chunk_id);
+    for (ticker, owner) in release_plan(tickers, superseded) {
+        invocation.arg(ticker).arg(owner);
+    }
+    let () = invocation.invoke_async(con).await?;
+    Ok(())
+}
+
+// (ticker, owner to restore or "" to free) for every ticker of the chunk
+fn release_plan(tickers: &[String], superseded: &[Superseded]) -> Vec<(String, String)> {
+    tickers
+        .iter()
+        .map(|ticker| {
+            let owner = superseded.iter().find(|s| s.tickers.contains(ticker)).map(|s| s.chunk_id.to_string()).unwrap_or_default();
+            (ticker.clone(), owner)
+        })
+        .collect()
+}
+
+// KEYS[1] job:{id}:tickers, ARGV: chunk id, claim mode, TTL, tickers...
+const CLAIM_SCRIPT: &str = r#"
+local owners = {}
+local taken = false
+for i = 4, #ARGV do
+    local owner = redis.call('HGET', KEYS[1], ARGV[i])
+    if owner and owner ~= ARGV[1] then
+        owners[#owners + 1] = owner
+        taken = true
+    else
+        owners[#owners + 1] = ''
+    end
+end
+if ARGV[2] == 'none-if-taken' and taken then
+    return owners
+end
+for i = 4, #ARGV do
+    if owners[i - 3] == '' or ARGV[2] == 'all' then
+        redis.call('HSET', KEYS[1], ARGV[i], ARGV[1])
+    end
+end
+redis.call('EXPIRE', KEYS[1], ARGV[3])
+return owners
+"#;
+
+// KEYS[1] job:{id}:tickers, ARGV: chunk id, then ticker / owner pairs
+const RELEASE_SCRIPT: &str = r#"
+for i = 2, #ARGV, 2 do
+    if redis.call('HGET', KEYS[1], ARGV[i]) == ARGV[1] then
+        if ARGV[i + 1] == '' then
+            redis.call('HDEL', KEYS[1], ARGV[i])
+        else
+            redis.call('HSET', KEYS[1], ARGV[i], ARGV[i + 1])
+        end
+    end
+end
+"#;
+
+// Ticker attribute of every <Asset>, in document order
+pub fn tickers_in(xml: &str) -> Result<Vec<String>> {
+    let mut reader = Reader::from_str(xml);
+    let mut tickers = Vec::new();
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) | Event::Empty(e) if e.local_name().as_ref() == b"Asset" => {
+                if let Some(attr) = e.try_get_attribute("Ticker")? {
+                    tickers.push(attr.unescape_value()?.into_owned());
+                }
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+    }
+    Ok(tickers)
+}
+
+// Copies the document, leaving out <Asset> elements whose Ticker is in `drop`
+pub fn strip_assets(xml: &str, drop: &HashSet<String>) -> Result<String> {
+    let mut reader = Reader::from_str(xml);
+    let mut writer = Writer::new(Vec::new());
+    let mut skip_depth = 0usize;
+    loop {
+        let event = reader.read_event()?;
+        if skip_depth > 0 {
+            match event {
+                Event::Start(_) => skip_depth += 1,
+                Event::End(_) => skip_depth -= 1,
+                Event::Eof => break,
+                _ => (),
+            }
+            continue;
+        }
+        match &event {
+            Event::Start(e) | Event::Empty(e) if e.local_name().as_ref() == b"Asset" => {
+                let ticker = e.try_get_attribute("Ticker")?.map(|a| a.unescape_value().map(|v| v.into_owned())).transpose()?;
+                if ticker.is_some_and(|t| drop.contains(&t)) {
+                    if matches!(event, Event::Start(_)) {
+                        skip_depth = 1;
+                    }
+                    continue;
+                }
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+        writer.write_event(event)?;
+    }
+    Ok(String::from_utf8(writer.into_inner())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REPORT: &str = concat!(
+        r#"<mr:MarketReport xmlns:mr="urn:tp3:market-report:v1" JobID="j">"#,
+        r#"<Asset Ticker="AAPL"><Identification><Name>Apple</Name></Identification></Asset>"#,
+        r#"<Asset Ticker="MSFT"><Identification><Name>Microsoft</Name></Identification></Asset>"#,
+        r#"<Asset Ticker="T&amp;T"/>"#,
+        r#"</mr:MarketReport>"#,
+    );
+
+    fn drop(tickers: &[&str]) -> HashSet<String> {
+        tickers.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn lists_tickers_in_order() {
+        assert_eq!(tickers_in(REPORT).unwrap(), ["AAPL", "MSFT", "T&T"]);
+        assert!(tickers_in("<MarketReport/>").unwrap().is_empty());
+    }
+
+    #[test]
+    fn strips_nested_and_empty_assets() {
+        let stripped = strip_assets(REPORT, &drop(&["AAPL", "T&T"])).unwrap();
+        assert_eq!(tickers_in(&stripped).unwrap(), ["MSFT"]);
+        assert!(stripped.starts_with(r#"<mr:MarketReport xmlns:mr="urn:tp3:market-report:v1" JobID="j">"#));
+        assert!(stripped.contains("<Name>Microsoft</Name>"));
+        assert!(!stripped.contains("Apple"));
+        assert!(stripped.ends_with("</mr:MarketReport>"));
+    }
+
+    #[test]
+    fn keeps_the_document_when_nothing_matches() {
+        assert_eq!(strip_assets(REPORT, &drop(&["TSLA"])).unwrap(), REPORT);
+        let all = strip_assets(REPORT, &drop(&["AAPL", "MSFT", "T&T"])).unwrap();
+        assert_eq!(all, r#"<mr:MarketReport xmlns:mr="urn:tp3:market-report:v1" JobID="j"></mr:MarketReport>"#);
+    }
+
+    #[test]
+    fn release_restores_taken_over_tickers_and_frees_the_rest() {
+        let tickers: Vec<String> = ["AAPL", "MSFT", "TSLA"].iter().map(|t| t.to_string()).collect();
+        let superseded = [
+            Superseded { chunk_id: 3, tickers: vec!["MSFT".to_string()] },
+            Superseded { chunk_id: 1, tickers: vec!["TSLA".to_string()] },
+        ];
+        let plan = release_plan(&tickers, &superseded);
+        let plan: Vec<(&str, &str)> = plan.iter().map(|(t, o)| (t.as_str(), o.as_str())).collect();
+        assert_eq!(plan, [("AAPL", ""), ("MSFT", "3"), ("TSLA", "1")]);
+        assert!(release_plan(&tickers, &[]).iter().all(|(_, owner)| owner.is_empty()));
+    }
+
+    #[test]
+    fn claim_modes_match_the_script() {
+        for (mode, name) in [(ClaimMode::Free, "free"), (ClaimMode::All, "all"), (ClaimMode::NoneIfTaken, "none-if-taken")] {
+            assert_eq!(mode.as_str(), name);
+            assert!(mode == ClaimMode::Free || CLAIM_SCRIPT.contains(&format!("'{}'", name)));
+        }
+    }
+
+    #[test]
+    fn superseded_round_trips() {
+        let superseded = Superseded { chunk_id: 3, tickers: vec!["AAPL".to_string()] };
+        let json = serde_json::to_string(&superseded).unwrap();
+        assert_eq!(json, r#"{"chunk_id":3,"tickers":["AAPL"]}"#);
+        assert_eq!(serde_json::from_str::<Superseded>(&json).unwrap(), superseded);
+    }
+}
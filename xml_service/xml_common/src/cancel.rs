@@ -0,0 +1,135 @@
+use chrono::Utc;
+use redis::AsyncCommands;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::redis_conn;
+
+// A cancelled job is marked with
+//   job:{id}:cancelled  hash: requested_at, reason, delete_rows ("1" / "0")
+// by `db_sender cancel` or the gRPC CancelJob RPC. Every worker checks it on
+// pop and drops the job's remaining chunks; db_sender's supervisor then deletes
+// the stored rows if asked to and finalises the job as CANCELLED.
+// The marker outlives the job's other keys so chunks still queued keep being dropped.
+const CANCEL_TTL_SECS: i64 = 86400;
+
+// Jobs that have started but not been finalised; scanned by db_sender's
+// supervisor. A cancelled job is (re)added so the supervisor picks it up.
+pub const ACTIVE_JOBS_KEY: &str = "jobs:active";
+
+#[derive(Debug, PartialEq)]
+pub struct Cancellation {
+    pub reason: String,
+    pub delete_rows: bool,
+}
+
+#[derive(Debug)]
+pub enum RequestError {
+    Finished { job_id: String, status: String },
+    Unknown { job_id: String },
+    Redis(redis::RedisError),
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RequestError::Finished { job_id, status } => write!(f, "Job {} already finished as {}", job_id, status),
+            RequestError::Unknown { job_id } => write!(f, "Job {} is not known (never started or expired)", job_id),
+            RequestError::Redis(e) => write!(f, "Redis Error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+impl From<redis::RedisError> for RequestError {
+    fn from(e: redis::RedisError) -> Self {
+        RequestError::Redis(e)
+    }
+}
+
+fn cancelled_key(job_id: &str) -> String {
+    format!("job:{}:cancelled", job_id)
+}
+
+pub async fn is_cancelled(con: &mut redis_conn::Connection, job_id: &str) -> bool {
+    match con.exists(cancelled_key(job_id)).await {
+        Ok(cancelled) => cancelled,
+        Err(e) => {
+            tracing::warn!(job_id, error = %e, "cancel.check_failed");
+            false
+        }
+    }
+}
+
+pub async fn requested(con: &mut redis_conn::Connection, job_id: &str) -> redis::RedisResult<Option<Cancellation>> {
+    let fields: HashMap<String, String> = con.hgetall(cancelled_key(job_id)).await?;
+    Ok(parse(&fields))
+}
+
+fn parse(fields: &HashMap<String, String>) -> Option<Cancellation> {
+    if fields.is_empty() {
+        return None;
+    }
+    Some(Cancellation {
+        reason: fields.get("reason").cloned().unwrap_or_default(),
+        delete_rows: fields.get("delete_rows").map(String::as_str) == Some("1"),
+    })
+}
+
+// Marks a running job cancelled; refuses jobs already finalised or never seen
+pub async fn request(con: &mut redis_conn::Connection, job_id: &str, reason: &str, delete_rows: bool) -> Result<(), RequestError> {
+    let finalized: Option<String> = con.get(format!("job:{}:finalized", job_id)).await?;
+    if let Some(status) = finalized {
+        return Err(RequestError::Finished { job_id: job_id.to_string(), status });
+    }
+    let active: bool = con.sismember(ACTIVE_JOBS_KEY, job_id).await?;
+    let known: bool = con.exists(format!("job:{}:total", job_id)).await?;
+    if !active && !known {
+        return Err(RequestError::Unknown { job_id: job_id.to_string() });
+    }
+
+    let key = cancelled_key(job_id);
+    let fields = [
+        ("requested_at", Utc::now().to_rfc3339()),
+        ("reason", reason.to_string()),
+        ("delete_rows", if delete_rows { "1" } else { "0" }.to_string()),
+    ];
+    let _: () = redis::pipe()
+        .hset_multiple(&key, &fields).ignore()
+        .expire(&key, CANCEL_TTL_SECS).ignore()
+        .sadd(ACTIVE_JOBS_KEY, job_id).ignore()
+        .query_async(con)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn marker(fields: &[(&str, &str)]) -> HashMap<String, String> {
+        fields.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn reads_the_cancel_marker() {
+        assert_eq!(parse(&marker(&[])), None);
+        assert_eq!(
+            parse(&marker(&[("requested_at", "2026-01-01T10:00:00Z"), ("reason", "wrong file"), ("delete_rows", "1")])),
+            Some(Cancellation { reason: "wrong file".to_string(), delete_rows: true })
+        );
+        assert_eq!(
+            parse(&marker(&[("requested_at", "2026-01-01T10:00:00Z"), ("delete_rows", "0")])),
+            Some(Cancellation { reason: String::new(), delete_rows: false })
+        );
+    }
+
+    #[test]
+    fn refusals_name_the_job() {
+        let finished = RequestError::Finished { job_id: "job-1".to_string(), status: "COMPLETED".to_string() };
+        assert_eq!(finished.to_string(), "Job job-1 already finished as COMPLETED");
+        let unknown = RequestError::Unknown { job_id: "job-2".to_string() };
+        assert_eq!(unknown.to_string(), "Job job-2 is not known (never started or expired)");
+    }
+}
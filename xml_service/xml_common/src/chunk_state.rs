@@ -0,0 +1,70 @@
+use chrono::Utc;
+
+use crate::redis_conn;
+
+// Chunk lifecycle shared by all workers, kept in Redis so operators can see
+// where each chunk of a job is and how long every stage took:
+//   job:{id}:chunk:{chunk_id}  hash: state, updated_at, <state>_at, error
+//   job:{id}:tracked_chunks    set of chunk ids with a state hash
+// States, in order: queued, converting, converted, validating, validated,
+// persisting, done | failed; any stage may end in cancelled instead. A chunk
+// the converter or validator rejects ends failed there and skips persisting.
+const STATE_TTL_SECS: i64 = 86400;
+
+// A transition without an error clears the one a failed attempt left, so a
+// chunk redone after a failure doesn't show it once done
+pub async fn record(con: &mut redis_conn::Connection, job_id: &str, chunk_id: u32, state: &str, error: Option<&str>) {
+    let result: redis::RedisResult<()> = transition(job_id, chunk_id, state, error, &Utc::now().to_rfc3339())
+        .query_async(con)
+        .await;
+    if let Err(e) = result {
+        tracing::warn!(job_id, chunk_id, state, error = %e, "chunk_state.record_failed");
+    }
+}
+
+fn transition(job_id: &str, chunk_id: u32, state: &str, error: Option<&str>, now: &str) -> redis::Pipeline {
+    let key = format!("job:{}:chunk:{}", job_id, chunk_id);
+    let index_key = format!("job:{}:tracked_chunks", job_id);
+    let fields = [
+        ("state".to_string(), state.to_string()),
+        ("updated_at".to_string(), now.to_string()),
+        (format!("{}_at", state), now.to_string()),
+    ];
+
+    let mut pipe = redis::pipe();
+    pipe.hset_multiple(&key, &fields).ignore();
+    match error {
+        Some(error) => pipe.hset(&key, "error", error).ignore(),
+        None => pipe.hdel(&key, "error").ignore(),
+    };
+    pipe.expire(&key, STATE_TTL_SECS).ignore()
+        .sadd(&index_key, chunk_id).ignore()
+        .expire(&index_key, STATE_TTL_SECS).ignore();
+    pipe
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The pipeline's commands as RESP text, e.g. "HDEL" followed by its args
+    fn commands(pipe: &redis::Pipeline) -> String {
+        String::from_utf8_lossy(&pipe.get_packed_pipeline()).into_owned()
+    }
+
+    #[test]
+    fn failures_record_their_error() {
+        let sent = commands(&transition("job-1", 3, "failed", Some("validation: 2 finding(s)"), "2026-01-01T10:00:00Z"));
+        assert!(sent.contains("job:job-1:chunk:3"));
+        assert!(sent.contains("failed_at"));
+        assert!(sent.contains("validation: 2 finding(s)"));
+        assert!(!sent.contains("HDEL"));
+    }
+
+    #[test]
+    fn later_transitions_clear_the_error() {
+        let sent = commands(&transition("job-1", 3, "done", None, "2026-01-01T10:00:00Z"));
+        assert!(sent.contains("done_at"));
+        assert!(sent.contains("HDEL\r\n$17\r\njob:job-1:chunk:3\r\n$5\r\nerror"));
+    }
+}
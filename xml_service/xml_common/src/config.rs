@@ -0,0 +1,528 @@
+use anyhow::{bail, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::env;
+
+use crate::secret::Secret;
+
+// Service configuration, in layers where later ones win:
+//   1. the defaults of the service's Config
+//   2. a TOML file given by --config <path> or CONFIG_FILE (see each service's
+//      config.example.toml)
+//   3. environment variables, from SHARED_ENV_KEYS and the service's own
+//      ENV_KEYS; each can instead name a file to read the value from, e.g.
+//      REDIS_PASSWORD_FILE
+//   4. command line flags named after the TOML keys: --redis.host cache-1,
+//      --worker.concurrency=4
+// The result is checked once at startup, so a bad value stops the service
+// before it takes any message.
+pub trait Settings: Serialize + DeserializeOwned + Default {
+    // Environment variables for the service's own keys, as (key, VAR)
+    const ENV_KEYS: &'static [(&'static str, &'static str)];
+
+    fn validate(&self, keys: &EnvKeys) -> Result<()>;
+}
+
+// Variables of the sections below. One only applies to a service whose Config
+// has that key, so e.g. DATABASE_URL is no concern of the converter.
+const SHARED_ENV_KEYS: &[(&str, &str)] = &[
+    ("redis.url", "REDIS_URL"),
+    ("redis.host", "REDIS_HOST"),
+    ("redis.port", "REDIS_PORT"),
+    ("redis.username", "REDIS_USERNAME"),
+    ("redis.password", "REDIS_PASSWORD"),
+    ("redis.db", "REDIS_DB"),
+    ("redis.tls", "REDIS_TLS"),
+    ("redis.tls_insecure", "REDIS_TLS_INSECURE"),
+    ("redis.sentinel.master_name", "REDIS_SENTINEL_MASTER"),
+    ("redis.sentinel.nodes", "REDIS_SENTINEL_NODES"),
+    ("redis.cluster.nodes", "REDIS_CLUSTER_NODES"),
+    ("database.url", "DATABASE_URL"),
+    ("queues.csv_processing", "QUEUE_CSV_PROCESSING"),
+    ("queues.xml_validation", "QUEUE_XML_VALIDATION"),
+    ("queues.db_persistence", "QUEUE_DB_PERSISTENCE"),
+    ("worker.concurrency", "WORKER_CONCURRENCY"),
+    ("worker.poll_timeout_secs", "POLL_TIMEOUT_SECS"),
+    ("worker.shutdown_timeout_secs", "SHUTDOWN_TIMEOUT_SECS"),
+    ("metrics.port", "METRICS_PORT"),
+    ("log.level", "LOG_LEVEL"),
+    ("log.format", "LOG_FORMAT"),
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedisConfig {
+    // redis://, rediss:// or redis+unix:// URL of a single server, instead of
+    // host/port/tls
+    pub url: Secret,
+    pub host: String,
+    pub port: u16,
+    // ACL user and password, database index: when set they override the ones
+    // in url and the node URLs, so credentials can stay out of the URL
+    pub username: String,
+    pub password: Secret,
+    pub db: i64,
+    pub tls: bool,
+    // Skips certificate and hostname checks; only for self-signed test setups
+    pub tls_insecure: bool,
+    pub sentinel: SentinelConfig,
+    pub cluster: ClusterConfig,
+}
+
+impl Default for RedisConfig {
+    fn default() -> Self {
+        RedisConfig {
+            url: Secret::default(),
+            host: String::new(),
+            port: 6379,
+            username: String::new(),
+            password: Secret::default(),
+            db: 0,
+            tls: false,
+            tls_insecure: false,
+            sentinel: SentinelConfig::default(),
+            cluster: ClusterConfig::default(),
+        }
+    }
+}
+
+// Master found through Sentinel. Nodes are host:port (tls applies) or URLs;
+// username/password are the master's, a sentinel's own login goes in its URL.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SentinelConfig {
+    pub master_name: String,
+    pub nodes: Vec<String>,
+}
+
+// Any subset of a cluster's nodes, as host:port or URLs
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClusterConfig {
+    pub nodes: Vec<String>,
+}
+
+impl RedisConfig {
+    pub fn is_configured(&self) -> bool {
+        !self.url.is_empty() || !self.host.is_empty() || !self.sentinel.nodes.is_empty() || !self.cluster.nodes.is_empty()
+    }
+
+    // URLs are parsed by redis_conn::Redis::open, right after loading
+    pub fn validate(&self, keys: &EnvKeys) -> Result<()> {
+        if !self.is_configured() {
+            bail!("redis.host is required ({}), or redis.url, redis.sentinel.nodes or redis.cluster.nodes", keys.sources("redis.host"));
+        }
+        let targets = [!self.url.is_empty(), !self.host.is_empty(), !self.sentinel.nodes.is_empty(), !self.cluster.nodes.is_empty()];
+        if targets.iter().filter(|set| **set).count() > 1 {
+            bail!("Set only one of redis.url, redis.host, redis.sentinel.nodes and redis.cluster.nodes");
+        }
+        if !self.sentinel.nodes.is_empty() {
+            keys.required("redis.sentinel.master_name", &self.sentinel.master_name)?;
+        }
+        if !self.cluster.nodes.is_empty() && self.db != 0 {
+            bail!("redis.db must be 0 with redis.cluster.nodes, a cluster only has database 0");
+        }
+        if self.db < 0 {
+            bail!("redis.db must be at least 0, got {} ({})", self.db, keys.sources("redis.db"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    // postgres:// URL; sslmode=require is added unless it sets sslmode itself
+    pub url: Secret,
+}
+
+// Names of the Redis lists between the stages. They must agree across the
+// services, so every service takes all of them from the same keys, even the
+// ones it doesn't use. The Python enricher feeds csv_processing and reads the
+// same QUEUE_CSV_PROCESSING variable.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Queues {
+    pub csv_processing: String,
+    pub xml_validation: String,
+    pub db_persistence: String,
+}
+
+impl Default for Queues {
+    fn default() -> Self {
+        Queues {
+            csv_processing: "queue:csv_processing".to_string(),
+            xml_validation: "queue:xml_validation".to_string(),
+            db_persistence: "queue:db_persistence".to_string(),
+        }
+    }
+}
+
+impl Queues {
+    pub fn validate(&self, keys: &EnvKeys) -> Result<()> {
+        let queues = [&self.csv_processing, &self.xml_validation, &self.db_persistence];
+        for (key, name) in ["queues.csv_processing", "queues.xml_validation", "queues.db_persistence"].iter().zip(queues) {
+            keys.required(key, name)?;
+        }
+        if queues[0] == queues[1] || queues[1] == queues[2] || queues[0] == queues[2] {
+            bail!("queues.csv_processing, queues.xml_validation and queues.db_persistence must all differ");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorkerConfig {
+    pub concurrency: usize,
+    // BLPOP timeout; also how often an idle worker reports liveness
+    pub poll_timeout_secs: u64,
+    // How long the message in flight may take after SIGTERM. Keep it under
+    // the orchestrator's grace period (Docker's stop timeout is 10s) or the
+    // process is killed before it can requeue the message.
+    pub shutdown_timeout_secs: u64,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        WorkerConfig { concurrency: 1, poll_timeout_secs: 5, shutdown_timeout_secs: 8 }
+    }
+}
+
+impl WorkerConfig {
+    pub fn validate(&self, keys: &EnvKeys) -> Result<()> {
+        keys.at_least("worker.concurrency", self.concurrency as u64, 1)?;
+        keys.at_least("worker.poll_timeout_secs", self.poll_timeout_secs, 1)
+    }
+}
+
+// No Default: every service has a port of its own, set in its Config's
+// Default (which serde falls back to when [metrics] is left out)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub port: u16,
+}
+
+impl MetricsConfig {
+    pub fn validate(&self, keys: &EnvKeys) -> Result<()> {
+        keys.at_least("metrics.port", self.port as u64, 1)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    // EnvFilter directive, e.g. "info" or "db_sender=debug,tokio_postgres=warn"
+    pub level: String,
+    // json or text
+    pub format: String,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig { level: "info".to_string(), format: "json".to_string() }
+    }
+}
+
+impl LogConfig {
+    pub fn validate(&self) -> Result<()> {
+        tracing_subscriber::EnvFilter::try_new(&self.level).with_context(|| format!("Invalid log.level '{}'", self.level))?;
+        if self.format != "json" && self.format != "text" {
+            bail!("log.format must be json or text, got '{}'", self.format);
+        }
+        Ok(())
+    }
+}
+
+// The environment variables a service reads, for error messages that say
+// where a key can be set
+pub struct EnvKeys(Vec<(&'static str, &'static str)>);
+
+impl EnvKeys {
+    pub fn of<T: Settings>() -> EnvKeys {
+        let defaults = toml::Value::try_from(T::default()).ok();
+        let shared = SHARED_ENV_KEYS.iter().filter(|(key, _)| defaults.as_ref().and_then(|d| lookup(d, key)).is_some());
+        EnvKeys(shared.chain(T::ENV_KEYS).copied().collect())
+    }
+
+    pub fn required(&self, key: &str, value: &str) -> Result<()> {
+        if value.trim().is_empty() {
+            bail!("{} is required ({})", key, self.sources(key));
+        }
+        Ok(())
+    }
+
+    pub fn at_least(&self, key: &str, value: u64, min: u64) -> Result<()> {
+        if value < min {
+            bail!("{} must be at least {}, got {} ({})", key, min, value, self.sources(key));
+        }
+        Ok(())
+    }
+
+    pub fn sources(&self, key: &str) -> String {
+        match self.0.iter().find(|(k, _)| *k == key) {
+            Some((_, var)) => format!("set {}, --{} or {} in the config file", var, key, key),
+            None => format!("set --{} or {} in the config file", key, key),
+        }
+    }
+}
+
+// Builds the configuration from all layers. Returns the arguments that aren't
+// configuration flags, e.g. a subcommand and its own options.
+pub fn load<T: Settings>(args: Vec<String>) -> Result<(T, Vec<String>)> {
+    load_from(args, |var| env::var(var).ok())
+}
+
+fn load_from<T: Settings>(args: Vec<String>, var: impl Fn(&str) -> Option<String>) -> Result<(T, Vec<String>)> {
+    let var = |name: &str| var(name).filter(|v| !v.is_empty());
+    let defaults = toml::Value::try_from(T::default())?;
+    let keys = EnvKeys::of::<T>();
+    let args = split_args(args)?;
+
+    let mut table = match args.file.or_else(|| var("CONFIG_FILE")) {
+        Some(path) => {
+            let text = std::fs::read_to_string(&path).with_context(|| format!("Failed to read config file {}", path))?;
+            toml::from_str(&text).with_context(|| format!("Invalid config file {}", path))?
+        }
+        None => toml::Table::new(),
+    };
+    for (key, name) in &keys.0 {
+        if let Some(raw) = env_value(name, &var)? {
+            set(&mut table, &defaults, key, &raw).with_context(|| format!("Invalid {}", name))?;
+        }
+    }
+    for (key, raw) in args.flags {
+        set(&mut table, &defaults, &key, &raw).with_context(|| format!("Invalid --{}", key))?;
+    }
+
+    let config: T = toml::Value::Table(table).try_into().context("Invalid configuration")?;
+    config.validate(&keys)?;
+    Ok((config, args.rest))
+}
+
+// The variable's value, or the contents of the file named by <var>_FILE
+// (Docker/Kubernetes secrets) without its trailing newline
+fn env_value(name: &str, var: &impl Fn(&str) -> Option<String>) -> Result<Option<String>> {
+    let value = var(name);
+    let file_var = format!("{}_FILE", name);
+    match var(&file_var) {
+        Some(_) if value.is_some() => bail!("Set {} or {}, not both", name, file_var),
+        Some(path) => {
+            let text = std::fs::read_to_string(&path).with_context(|| format!("Failed to read {} {}", file_var, path))?;
+            Ok(Some(text.trim_end_matches(['\r', '\n']).to_string()))
+        }
+        None => Ok(value),
+    }
+}
+
+// --config and --<section>.<key> flags, separated from the other arguments
+struct Args {
+    file: Option<String>,
+    flags: Vec<(String, String)>,
+    rest: Vec<String>,
+}
+
+fn split_args(args: Vec<String>) -> Result<Args> {
+    let mut file = None;
+    let mut flags = Vec::new();
+    let mut rest = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let Some(name) = arg.strip_prefix("--") else {
+            rest.push(arg);
+            continue;
+        };
+        let (name, inline) = match name.split_once('=') {
+            Some((name, value)) => (name.to_string(), Some(value.to_string())),
+            None => (name.to_string(), None),
+        };
+        if name != "config" && !name.contains('.') {
+            rest.push(arg);
+            continue;
+        }
+        let value = match inline {
+            Some(value) => value,
+            None => args.next().with_context(|| format!("--{} needs a value", name))?,
+        };
+        if name == "config" {
+            file = Some(value);
+        } else {
+            flags.push((name, value));
+        }
+    }
+    Ok(Args { file, flags, rest })
+}
+
+fn lookup<'a>(defaults: &'a toml::Value, key: &str) -> Option<&'a toml::Value> {
+    key.split('.').try_fold(defaults, |v, part| v.get(part))
+}
+
+// Puts `raw` at `key`, typed after the default found there
+fn set(table: &mut toml::Table, defaults: &toml::Value, key: &str, raw: &str) -> Result<()> {
+    let value = match lookup(defaults, key) {
+        Some(toml::Value::Integer(_)) => toml::Value::Integer(raw.trim().parse().with_context(|| format!("'{}' is not a whole number", raw))?),
+        Some(toml::Value::Boolean(_)) => toml::Value::Boolean(raw.trim().parse().with_context(|| format!("'{}' is not true or false", raw))?),
+        Some(toml::Value::Float(_)) => toml::Value::Float(raw.trim().parse().with_context(|| format!("'{}' is not a number", raw))?),
+        Some(toml::Value::Array(_)) | Some(toml::Value::Table(_)) => {
+            let json: serde_json::Value = serde_json::from_str(raw).context("expected JSON")?;
+            toml::Value::try_from(json)?
+        }
+        _ => toml::Value::String(raw.to_string()),
+    };
+    let (path, leaf) = key.rsplit_once('.').unwrap_or(("", key));
+    let mut section = table;
+    for part in path.split('.').filter(|p| !p.is_empty()) {
+        section = match section.entry(part).or_insert_with(|| toml::Value::Table(toml::Table::new())) {
+            toml::Value::Table(t) => t,
+            _ => bail!("{} is not a section", part),
+        };
+    }
+    section.insert(leaf.to_string(), value);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    struct TestConfig {
+        redis: RedisConfig,
+        queues: Queues,
+        worker: WorkerConfig,
+        metrics: MetricsConfig,
+        log: LogConfig,
+        extra: Extra,
+    }
+
+    #[derive(Debug, Default, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    struct Extra {
+        name: String,
+        ratio: f64,
+        tags: Vec<String>,
+    }
+
+    impl Default for TestConfig {
+        fn default() -> Self {
+            TestConfig {
+                redis: RedisConfig::default(),
+                queues: Queues::default(),
+                worker: WorkerConfig::default(),
+                metrics: MetricsConfig { port: 9999 },
+                log: LogConfig::default(),
+                extra: Extra::default(),
+            }
+        }
+    }
+
+    impl Settings for TestConfig {
+        const ENV_KEYS: &'static [(&'static str, &'static str)] = &[("extra.name", "EXTRA_NAME")];
+
+        fn validate(&self, keys: &EnvKeys) -> Result<()> {
+            self.redis.validate(keys)?;
+            self.queues.validate(keys)?;
+            self.worker.validate(keys)?;
+            self.metrics.validate(keys)?;
+            self.log.validate()
+        }
+    }
+
+    fn load(args: &[&str], env: &[(&str, &str)]) -> Result<(TestConfig, Vec<String>)> {
+        let env: HashMap<String, String> = env.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        load_from(args.iter().map(|a| a.to_string()).collect(), |var| env.get(var).cloned())
+    }
+
+    fn error(args: &[&str], env: &[(&str, &str)]) -> String {
+        format!("{:#}", load(args, env).unwrap_err())
+    }
+
+    fn temp_file(name: &str, text: &str) -> String {
+        let path = env::temp_dir().join(format!("xml_common-{}-{}", std::process::id(), name));
+        std::fs::write(&path, text).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn defaults_fill_what_is_not_set() {
+        let (config, rest) = load(&[], &[("REDIS_HOST", "cache")]).unwrap();
+        assert_eq!(config.redis.host, "cache");
+        assert_eq!(config.redis.port, 6379);
+        assert_eq!(config.queues.db_persistence, "queue:db_persistence");
+        assert_eq!(config.worker.concurrency, 1);
+        assert_eq!(config.metrics.port, 9999);
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn later_layers_win() {
+        let file = temp_file(
+            "layers.toml",
+            "[redis]\nhost = \"from-file\"\nport = 7000\n[worker]\nconcurrency = 2\npoll_timeout_secs = 9\n[metrics]\nport = 9100\n",
+        );
+        let env = [("CONFIG_FILE", file.as_str()), ("REDIS_PORT", "7001"), ("WORKER_CONCURRENCY", "3")];
+        let (config, rest) = load(&["serve", "--worker.concurrency", "4", "--verbose"], &env).unwrap();
+        assert_eq!(config.redis.host, "from-file");
+        assert_eq!(config.redis.port, 7001);
+        assert_eq!(config.worker.concurrency, 4);
+        assert_eq!(config.worker.poll_timeout_secs, 9);
+        assert_eq!(config.metrics.port, 9100);
+        assert_eq!(rest, ["serve", "--verbose"]);
+
+        let (config, _) = load(&["--config", &file, "--redis.port=7002"], &[]).unwrap();
+        assert_eq!(config.redis.port, 7002);
+    }
+
+    #[test]
+    fn values_can_come_from_files() {
+        let password = temp_file("password", "s3cret\n");
+        let (config, _) = load(&[], &[("REDIS_HOST", "cache"), ("REDIS_PASSWORD_FILE", &password)]).unwrap();
+        assert_eq!(config.redis.password.expose(), "s3cret");
+
+        let both = [("REDIS_HOST", "cache"), ("REDIS_PASSWORD", "x"), ("REDIS_PASSWORD_FILE", password.as_str())];
+        assert!(error(&[], &both).contains("Set REDIS_PASSWORD or REDIS_PASSWORD_FILE, not both"));
+    }
+
+    #[test]
+    fn values_are_typed_after_the_defaults() {
+        let env = [("REDIS_SENTINEL_NODES", r#"["s1:26379","s2:26379"]"#), ("REDIS_SENTINEL_MASTER", "main"), ("REDIS_TLS", "true")];
+        let (config, _) = load(&["--extra.ratio=0.5", "--extra.tags", r#"["a"]"#], &env).unwrap();
+        assert_eq!(config.redis.sentinel.nodes, ["s1:26379", "s2:26379"]);
+        assert!(config.redis.tls);
+        assert_eq!(config.extra.ratio, 0.5);
+        assert_eq!(config.extra.tags, ["a"]);
+
+        let message = error(&[], &[("REDIS_HOST", "cache"), ("REDIS_PORT", "high")]);
+        assert!(message.contains("Invalid REDIS_PORT: 'high' is not a whole number"), "{}", message);
+        assert!(error(&["--redis.host=cache", "--redis.tls=yes"], &[]).contains("Invalid --redis.tls"));
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(error(&["--redis.host=cache", "--redis.hots=x"], &[]).contains("unknown field `hots`"));
+        assert!(error(&["--redis.host=cache", "--worker.concurrency"], &[]).contains("--worker.concurrency needs a value"));
+    }
+
+    #[test]
+    fn shared_variables_only_apply_to_keys_the_service_has() {
+        let (config, _) = load(&[], &[("REDIS_HOST", "cache"), ("DATABASE_URL", "postgres://db/x"), ("EXTRA_NAME", "n")]).unwrap();
+        assert_eq!(config.extra.name, "n");
+
+        let keys = EnvKeys::of::<TestConfig>();
+        assert_eq!(keys.sources("database.url"), "set --database.url or database.url in the config file");
+        assert_eq!(keys.sources("extra.name"), "set EXTRA_NAME, --extra.name or extra.name in the config file");
+    }
+
+    #[test]
+    fn validation_says_where_to_set_a_key() {
+        let message = error(&[], &[]);
+        assert!(message.contains("set REDIS_HOST, --redis.host or redis.host in the config file"), "{}", message);
+        let message = error(&["--redis.host=cache", "--worker.concurrency=0"], &[]);
+        assert!(message.contains("worker.concurrency must be at least 1, got 0 (set WORKER_CONCURRENCY"), "{}", message);
+        let message = error(&["--redis.host=cache", "--queues.db_persistence=queue:xml_validation"], &[]);
+        assert!(message.contains("must all differ"), "{}", message);
+    }
+}
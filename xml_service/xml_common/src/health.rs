@@ -0,0 +1,150 @@
+use anyhow::Result;
+use chrono::Utc;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+
+use crate::redis_conn;
+
+// Health of a pipeline service, served next to /metrics (see metrics::serve)
+// and, in grpc_server, over grpc.health.v1 too:
+//   /healthz  liveness: the consume loop beat within the last
+//             LIVENESS_WINDOW_SECS. It beats after every BLPOP and every few
+//             seconds while a message is being handled (see worker), so a slow
+//             chunk keeps it alive but a blocked runtime doesn't. grpc_server
+//             has no loop and uses `responsive` instead.
+//   /readyz   readiness: every dependency check (redis, postgres, s3) passed
+//             on its last run and the service isn't shutting down
+// Both answer 200 or 503 with a JSON report of the checks and how long ago
+// the last message was processed successfully.
+const LIVENESS_WINDOW_SECS: i64 = 60;
+const CHECK_INTERVAL: Duration = Duration::from_secs(15);
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+static STARTED_AT: LazyLock<i64> = LazyLock::new(now);
+static DRAINING: AtomicBool = AtomicBool::new(false);
+static LAST_BEAT: AtomicI64 = AtomicI64::new(0);
+static LAST_SUCCESS: AtomicI64 = AtomicI64::new(0);
+static CHECKS: LazyLock<Mutex<BTreeMap<&'static str, CheckState>>> = LazyLock::new(Default::default);
+static READY: LazyLock<watch::Sender<bool>> = LazyLock::new(|| watch::channel(false).0);
+
+#[derive(Clone, Serialize)]
+struct CheckState {
+    ok: bool,
+    checked_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    status: &'a str,
+    checks: BTreeMap<&'static str, CheckState>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_beat_secs_ago: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_message_secs_ago: Option<i64>,
+}
+
+fn now() -> i64 {
+    Utc::now().timestamp()
+}
+
+fn secs_ago(at: &AtomicI64) -> Option<i64> {
+    match at.load(Ordering::Relaxed) {
+        0 => None,
+        t => Some(now() - t),
+    }
+}
+
+// Called by the consume loop while it's making progress
+pub fn beat() {
+    LazyLock::force(&STARTED_AT);
+    LAST_BEAT.store(now(), Ordering::Relaxed);
+}
+
+// Called once a message has been fully handled
+pub fn processed() {
+    LAST_SUCCESS.store(now(), Ordering::Relaxed);
+}
+
+// Set on SIGTERM so load balancers stop sending work while the loop drains
+pub fn draining() {
+    DRAINING.store(true, Ordering::Relaxed);
+    READY.send_replace(false);
+}
+
+// Follows readiness as it changes, for grpc.health.v1 Watch streams
+pub fn subscribe() -> watch::Receiver<bool> {
+    READY.subscribe()
+}
+
+pub fn is_ready() -> bool {
+    *READY.borrow()
+}
+
+// Runs `check` every CHECK_INTERVAL and keeps its last outcome for /readyz
+pub async fn watch<F, Fut>(name: &'static str, check: F)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    LazyLock::force(&STARTED_AT);
+    loop {
+        let error = match tokio::time::timeout(CHECK_TIMEOUT, check()).await {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => Some(e.to_string()),
+            Err(_) => Some(format!("no answer within {}s", CHECK_TIMEOUT.as_secs())),
+        };
+        if let Some(e) = &error {
+            tracing::warn!(check = name, error = %e, "health.check_failed");
+        }
+        let state = CheckState { ok: error.is_none(), checked_at: Utc::now().to_rfc3339(), error };
+        let ready = {
+            let mut checks = CHECKS.lock().unwrap();
+            checks.insert(name, state);
+            !DRAINING.load(Ordering::Relaxed) && checks.values().all(|c| c.ok)
+        };
+        READY.send_if_modified(|current| std::mem::replace(current, ready) != ready);
+        tokio::time::sleep(CHECK_INTERVAL).await;
+    }
+}
+
+pub fn liveness() -> (bool, String) {
+    // Until the loop first beats, the process gets the window to finish starting up
+    let alive = match secs_ago(&LAST_BEAT) {
+        Some(ago) => ago <= LIVENESS_WINDOW_SECS,
+        None => now() - *STARTED_AT <= LIVENESS_WINDOW_SECS,
+    };
+    (alive, report(alive))
+}
+
+// Liveness for a server without a consume loop: alive while it answers
+pub fn responsive() -> (bool, String) {
+    (true, report(true))
+}
+
+pub fn readiness() -> (bool, String) {
+    let ready = is_ready();
+    (ready, report(ready))
+}
+
+fn report(ok: bool) -> String {
+    let report = Report {
+        status: if ok { "ok" } else { "unavailable" },
+        checks: CHECKS.lock().unwrap().clone(),
+        last_beat_secs_ago: secs_ago(&LAST_BEAT),
+        last_message_secs_ago: secs_ago(&LAST_SUCCESS),
+    };
+    serde_json::to_string(&report).unwrap_or_default()
+}
+
+pub async fn redis_check(client: redis_conn::Redis) -> Result<()> {
+    let mut con = client.connect().await?;
+    let _: String = redis::cmd("PING").query_async(&mut con).await?;
+    Ok(())
+}
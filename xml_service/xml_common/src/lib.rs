@@ -0,0 +1,14 @@
+// Code shared by the pipeline services (converter, validator, db_sender and
+// grpc_server), so a fix lands once instead of once per binary.
+pub mod assets;
+pub mod cancel;
+pub mod chunk_state;
+pub mod config;
+pub mod health;
+pub mod logging;
+pub mod metrics;
+pub mod redis_conn;
+pub mod secret;
+pub mod shutdown;
+pub mod telemetry;
+pub mod worker;
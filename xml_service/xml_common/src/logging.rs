@@ -0,0 +1,24 @@
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::EnvFilter;
+
+use crate::config::LogConfig;
+use crate::telemetry;
+
+// JSON lines on stdout, one object per event with the current span's fields
+// (job_id, chunk_id, stage, mapper_version) so a chunk can be followed across
+// services. log.level takes an EnvFilter directive ("info", "debug",
+// "converter=debug,aws_config=warn"); log.format = "text" gives readable
+// output for local runs.
+// Events are named <subject>.<what happened> and mean the same thing in every
+// service (chunk.received, chunk.forwarded, chunk.failed, chunk.dropped, ...).
+pub fn init(service: &'static str, config: &LogConfig) {
+    // The directive was checked when the configuration was loaded
+    let filter = EnvFilter::try_new(&config.level).unwrap_or_else(|_| EnvFilter::new("info"));
+    let json = config.format != "text";
+    tracing_subscriber::registry()
+        .with(telemetry::layer(service))
+        .with(filter)
+        .with((!json).then(tracing_subscriber::fmt::layer))
+        .with(json.then(|| tracing_subscriber::fmt::layer().json().with_current_span(true).with_span_list(false).flatten_event(true)))
+        .init();
+}
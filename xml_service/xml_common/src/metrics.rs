@@ -0,0 +1,96 @@
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use prometheus::{register_int_counter_vec, register_int_gauge_vec, Encoder, IntCounterVec, IntGaugeVec, TextEncoder};
+use redis::AsyncCommands;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use crate::redis_conn;
+
+// Prometheus metrics served on METRICS_PORT at /metrics, next to the /healthz
+// and /readyz probes. The pipeline_* families are shared by every worker and
+// labelled by queue; each service registers its own histograms besides.
+pub static CONSUMED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("pipeline_messages_consumed_total", "Messages popped from a queue", &["queue"]).unwrap()
+});
+pub static PRODUCED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("pipeline_messages_produced_total", "Messages pushed to a queue", &["queue"]).unwrap()
+});
+pub static FAILED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("pipeline_messages_failed_total", "Messages popped from a queue that could not be processed", &["queue"]).unwrap()
+});
+pub static QUEUE_DEPTH: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!("pipeline_queue_depth", "Messages waiting in a queue", &["queue"]).unwrap()
+});
+
+// A probe's outcome and its JSON report, e.g. health::liveness
+pub type Probe = fn() -> (bool, String);
+
+pub async fn serve(port: u16, liveness: Probe, readiness: Probe) {
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let make_service =
+        make_service_fn(move |_| async move { Ok::<_, Infallible>(service_fn(move |req| handle(req, liveness, readiness))) });
+    match Server::try_bind(&addr) {
+        Ok(server) => {
+            tracing::info!(%addr, "metrics.listening");
+            if let Err(e) = server.serve(make_service).await {
+                tracing::error!(error = %e, "metrics.server_failed");
+            }
+        }
+        Err(e) => tracing::error!(%addr, error = %e, "metrics.bind_failed"),
+    }
+}
+
+async fn handle(req: Request<Body>, liveness: Probe, readiness: Probe) -> Result<Response<Body>, Infallible> {
+    let probe = match req.uri().path() {
+        "/metrics" => None,
+        "/healthz" => Some(liveness()),
+        "/readyz" => Some(readiness()),
+        _ => {
+            let mut not_found = Response::new(Body::empty());
+            *not_found.status_mut() = StatusCode::NOT_FOUND;
+            return Ok(not_found);
+        }
+    };
+    if let Some((ok, report)) = probe {
+        return Ok(Response::builder()
+            .status(if ok { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE })
+            .header("Content-Type", "application/json")
+            .body(Body::from(report))
+            .unwrap());
+    }
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        tracing::error!(error = %e, "metrics.encode_failed");
+    }
+    Ok(Response::builder()
+        .header("Content-Type", encoder.format_type())
+        .body(Body::from(buffer))
+        .unwrap())
+}
+
+// Samples LLEN of the given queues so backlog can be alerted on
+pub async fn watch_queues(client: redis_conn::Redis, queues: Vec<String>) {
+    let mut con = None;
+    loop {
+        if con.is_none() {
+            con = client.connect().await.ok();
+        }
+        if let Some(c) = con.as_mut() {
+            for queue in &queues {
+                match c.llen::<_, i64>(queue).await {
+                    Ok(depth) => QUEUE_DEPTH.with_label_values(&[queue]).set(depth),
+                    Err(e) => {
+                        tracing::warn!(queue = %queue, error = %e, "metrics.queue_depth_failed");
+                        con = None;
+                        break;
+                    }
+                }
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(15)).await;
+    }
+}
@@ -0,0 +1,76 @@
+use redis::AsyncCommands;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+use crate::health;
+use crate::redis_conn;
+
+// SIGTERM / SIGINT handling for the consume loop. Once a signal arrives the
+// loop stops popping, /readyz turns unavailable, and the message in flight
+// gets SHUTDOWN_TIMEOUT_SECS to finish (see `deadline`). If it doesn't, the
+// worker pushes it back to the head of its queue so the next consumer picks
+// it up; a message may therefore be handled twice, never lost.
+#[derive(Clone)]
+pub struct Shutdown {
+    rx: watch::Receiver<bool>,
+    timeout: Duration,
+}
+
+impl Shutdown {
+    pub fn listen(timeout: Duration) -> Self {
+        let (tx, rx) = watch::channel(false);
+        tokio::spawn(async move {
+            signal_received().await;
+            tracing::info!(timeout_secs = timeout.as_secs(), "shutdown.requested");
+            health::draining();
+            let _ = tx.send(true);
+        });
+        Shutdown { rx, timeout }
+    }
+
+    pub fn requested(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    // Completes `timeout` after the signal, never before it
+    pub async fn deadline(&self) {
+        let mut rx = self.rx.clone();
+        if rx.wait_for(|requested| *requested).await.is_ok() {
+            tokio::time::sleep(self.timeout).await;
+        } else {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+// Resolves on SIGTERM or SIGINT
+pub async fn signal_received() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(s) => s,
+        Err(e) => {
+            tracing::error!(error = %e, "shutdown.sigterm_unavailable");
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+    tokio::select! {
+        _ = terminate.recv() => (),
+        _ = tokio::signal::ctrl_c() => (),
+    }
+}
+
+// Puts an unfinished message back where BLPOP took it from: at the shutdown
+// deadline, or when the worker couldn't forward it. Uses its own connection
+// since the loop's one may be stuck in the abandoned handler.
+pub async fn requeue(client: &redis_conn::Redis, queue: &str, payload: &str, reason: &str) {
+    let result: redis::RedisResult<()> = async {
+        let mut con = client.connect().await?;
+        con.lpush(queue, payload).await
+    }
+    .await;
+    match result {
+        Ok(()) => tracing::warn!(queue, reason, "chunk.requeued"),
+        Err(e) => tracing::error!(queue, reason, error = %e, payload, "chunk.requeue_failed"),
+    }
+}
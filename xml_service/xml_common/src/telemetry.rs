@@ -0,0 +1,69 @@
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::global;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use std::collections::HashMap;
+use std::env;
+use std::sync::OnceLock;
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::Registry;
+
+// OpenTelemetry export of the tracing spans. Off unless
+// OTEL_EXPORTER_OTLP_ENDPOINT points at a collector (OTLP over HTTP, e.g.
+// http://otel-collector:4318); the standard OTEL_* variables apply.
+// Trace context travels between services as W3C `traceparent` entries in the
+// messages' `trace_context` map, so one chunk is a single trace end to end;
+// gRPC callers pass it as `traceparent` metadata.
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+pub fn layer(service: &'static str) -> Option<OpenTelemetryLayer<Registry, opentelemetry_sdk::trace::Tracer>> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    if env::var("OTEL_EXPORTER_OTLP_ENDPOINT").map(|v| v.is_empty()).unwrap_or(true) {
+        return None;
+    }
+
+    let exporter = match opentelemetry_otlp::SpanExporter::builder().with_http().build() {
+        Ok(exporter) => exporter,
+        Err(e) => {
+            eprintln!("OTLP exporter disabled: {}", e);
+            return None;
+        }
+    };
+    let service_name = env::var("OTEL_SERVICE_NAME").unwrap_or(service.to_string());
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(service_name).build())
+        .build();
+    let tracer = provider.tracer(service);
+    global::set_tracer_provider(provider.clone());
+    let _ = PROVIDER.set(provider);
+    Some(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+// Trace context of the current span, to put in an outgoing message
+pub fn inject() -> HashMap<String, String> {
+    let mut carrier = HashMap::new();
+    let cx = tracing::Span::current().context();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(&cx, &mut carrier));
+    carrier.retain(|_, v| !v.is_empty());
+    carrier
+}
+
+// Makes `span` a child of the trace an incoming message was sent from
+pub fn set_parent(span: &tracing::Span, carrier: &HashMap<String, String>) {
+    if carrier.is_empty() {
+        return;
+    }
+    let cx = global::get_text_map_propagator(|propagator| propagator.extract(carrier));
+    span.set_parent(cx);
+}
+
+// Flushes the spans still sitting in the batch exporter; called on the way out
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get() {
+        if let Err(e) = provider.shutdown() {
+            eprintln!("OTLP exporter shutdown failed: {}", e);
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use anyhow::Result;
+use redis::AsyncCommands;
+use serde::de::DeserializeOwned;
+use std::future::Future;
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tracing::Instrument;
+
+use crate::health;
+use crate::metrics;
+use crate::redis_conn;
+use crate::shutdown::{self, Shutdown};
+
+// Pause after a failed BLPOP, e.g. while Redis is down or failing over
+const POLL_RETRY_DELAY: Duration = Duration::from_secs(2);
+// How often a worker reports liveness while a message takes its time
+const BEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+// What a pipeline stage does with the messages it pops. The loop around it
+// (polling, invalid messages, requeueing on shutdown) is the same everywhere.
+pub trait Handler: Send + Sync + 'static {
+    type Message: DeserializeOwned + Send;
+
+    // The "chunk" span the message is handled in, with its trace parent set
+    fn span(&self, worker: usize, msg: &Self::Message) -> tracing::Span;
+
+    // An error means the result couldn't be passed on after its retries: the
+    // message goes back on the queue for a later try
+    fn handle<'a>(
+        &'a self,
+        con: &'a mut redis_conn::Connection,
+        msg: Self::Message,
+        payload: &'a str,
+    ) -> impl Future<Output = Result<()>> + Send + 'a;
+}
+
+// Runs one consumer of `queue` per handler, each on its own Redis connection,
+// until shutdown
+pub async fn run<H: Handler>(
+    client: &redis_conn::Redis,
+    queue: &str,
+    poll_timeout_secs: u64,
+    handlers: Vec<H>,
+    shutdown: &Shutdown,
+) -> Result<()> {
+    let mut workers = JoinSet::new();
+    for (worker, handler) in handlers.into_iter().enumerate() {
+        let con = client.connect().await?;
+        workers.spawn(consume(worker, con, queue.to_string(), poll_timeout_secs as f64, handler, shutdown.clone()));
+    }
+    // Workers only stop on shutdown; a panicking one is logged and the others
+    // keep draining
+    while let Some(result) = workers.join_next().await {
+        if let Err(e) = result {
+            tracing::error!(error = %e, "worker.failed");
+        }
+    }
+    Ok(())
+}
+
+async fn consume<H: Handler>(
+    worker: usize,
+    mut con: redis_conn::Connection,
+    queue: String,
+    poll_timeout: f64,
+    handler: H,
+    shutdown: Shutdown,
+) {
+    let client = con.client().clone();
+    while !shutdown.requested() {
+        // Finite timeout so the loop keeps reporting liveness while the queue is
+        // empty. A lost connection is reopened on the next poll.
+        let result: Option<(String, String)> = match con.blpop(&queue, poll_timeout).await {
+            Ok(result) => result,
+            Err(e) => {
+                tracing::warn!(worker, error = %e, "queue.poll_failed");
+                tokio::time::sleep(POLL_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        health::beat();
+        let Some((_, payload)) = result else { continue };
+        metrics::CONSUMED.with_label_values(&[&queue]).inc();
+        let msg: H::Message = match serde_json::from_str(&payload) {
+            Ok(msg) => msg,
+            Err(e) => {
+                tracing::error!(queue = %queue, error = %e, "message.invalid");
+                metrics::FAILED.with_label_values(&[&queue]).inc();
+                continue;
+            }
+        };
+        let span = handler.span(worker, &msg);
+        let handled = handler.handle(&mut con, msg, &payload).instrument(span.clone());
+        let deadline = shutdown.deadline();
+        tokio::pin!(handled, deadline);
+        let mut beat = tokio::time::interval(BEAT_INTERVAL);
+        let result = loop {
+            tokio::select! {
+                result = &mut handled => break Some(result),
+                _ = beat.tick() => health::beat(),
+                _ = &mut deadline => break None,
+            }
+        };
+        match result {
+            Some(Ok(())) => (),
+            Some(Err(e)) => {
+                span.in_scope(|| tracing::error!(error = %e, "chunk.forward_failed"));
+                metrics::FAILED.with_label_values(&[&queue]).inc();
+                shutdown::requeue(&client, &queue, &payload, "forward failed").await;
+                tokio::time::sleep(POLL_RETRY_DELAY).await;
+            }
+            // A message still in flight when the shutdown deadline passes goes
+            // back on the queue, so each stage must cope with handling it twice
+            None => {
+                shutdown::requeue(&client, &queue, &payload, "shutdown deadline").await;
+                break;
+            }
+        }
+    }
+}